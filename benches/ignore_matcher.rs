@@ -0,0 +1,83 @@
+// ./benches/ignore_matcher.rs
+//! Compares checking paths against a prebuilt `IgnoreMatcher` with walking
+//! each file's parent directory to see whether the file survives.
+//!
+//! Run with `cargo bench --bench ignore_matcher`.
+use create_context::{walk_builder, IgnoreMatcher, WalkOptions};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const DIRS: usize = 50;
+const FILES_PER_DIR: usize = 100;
+const RUNS: u32 = 5;
+
+/// Lays out `DIRS` directories of `FILES_PER_DIR` files under `root`, with a
+/// `.gitignore` that drops every tenth file.
+fn synthetic_tree(root: &Path) -> Vec<PathBuf> {
+    fs::create_dir(root.join(".git")).expect("create .git");
+    fs::write(root.join(".gitignore"), "*.log\n").expect("write .gitignore");
+    let mut files = Vec::new();
+    for d in 0..DIRS {
+        let dir = root.join(format!("module_{d}"));
+        fs::create_dir(&dir).expect("create dir");
+        for f in 0..FILES_PER_DIR {
+            let ext = if f % 10 == 0 { "log" } else { "rs" };
+            let path = dir.join(format!("file_{f}.{ext}"));
+            fs::write(&path, "fn main() {}\n").expect("write file");
+            files.push(path.canonicalize().expect("canonicalize"));
+        }
+    }
+    files
+}
+
+/// Returns the ignored count from the last run and the average time per run,
+/// including building the matcher.
+fn time_matcher(root: &Path, files: &[PathBuf], walk: &WalkOptions) -> (usize, Duration) {
+    let start = Instant::now();
+    let mut ignored = 0;
+    for _ in 0..RUNS {
+        let matcher = IgnoreMatcher::build(root, walk);
+        ignored = files.iter().filter(|path| matcher.is_ignored(path)).count();
+    }
+    (ignored, start.elapsed() / RUNS)
+}
+
+/// The per-file check the matcher replaced: walk the file's parent directory
+/// and look for the file among the survivors. Runs once, as it is slow.
+fn time_parent_walks(root: &Path, files: &[PathBuf], walk: &WalkOptions) -> (usize, Duration) {
+    let start = Instant::now();
+    let ignored = files
+        .iter()
+        .filter(|path| {
+            let parent = path.parent().unwrap_or(root);
+            !walk_builder(parent, walk)
+                .build()
+                .flatten()
+                .any(|entry| entry.path() == path.as_path())
+        })
+        .count();
+    (ignored, start.elapsed())
+}
+
+fn main() {
+    let tmp = tempfile::tempdir().expect("tempdir");
+    let root = tmp.path().canonicalize().expect("canonicalize");
+    let files = synthetic_tree(&root);
+    let walk = WalkOptions::default();
+
+    let (ignored, matcher_time) = time_matcher(&root, &files, &walk);
+    let (walked_ignored, walk_time) = time_parent_walks(&root, &files, &walk);
+    assert_eq!(
+        ignored, walked_ignored,
+        "matcher disagrees with parent walks"
+    );
+
+    println!("{} files, {} ignored", files.len(), ignored);
+    println!("matcher:      {:?}", matcher_time);
+    println!(
+        "parent walks: {:?} ({:.1}x slower)",
+        walk_time,
+        walk_time.as_secs_f64() / matcher_time.as_secs_f64()
+    );
+}
@@ -0,0 +1,56 @@
+// ./benches/token_counts.rs
+//! Compares serial and parallel per-file token counting.
+//!
+//! Run with `cargo bench --bench token_counts`.
+use create_context::{file_token_counts, load_tokenizer, ProcessedFile, RenderOptions, Tokenizer};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const FILES: usize = 1000;
+const RUNS: u32 = 5;
+
+fn synthetic_files() -> Vec<ProcessedFile> {
+    (0..FILES)
+        .map(|i| ProcessedFile {
+            path: format!("./src/module_{i}.rs"),
+            source: PathBuf::from(format!("./src/module_{i}.rs")),
+            language: "rust".to_string(),
+            content: format!(
+                "/// Adds {i} to the input.\npub fn add_{i}(x: u64) -> u64 {{\n    x + {i}\n}}\n\n"
+            )
+            .repeat(20 + i % 40),
+            git_status: None,
+            modified: None,
+            size: 0,
+            sha256: None,
+        })
+        .collect()
+}
+
+/// Returns the counts from the last run and the average time per run.
+fn time(parallel: bool, files: &[ProcessedFile]) -> (Vec<usize>, Duration) {
+    let bpe = load_tokenizer(Tokenizer::default()).expect("tokenizer");
+    let options = RenderOptions::default();
+    let start = Instant::now();
+    let mut counts = Vec::new();
+    for _ in 0..RUNS {
+        counts = file_token_counts(&bpe, files, &options, parallel);
+    }
+    (counts, start.elapsed() / RUNS)
+}
+
+fn main() {
+    let files = synthetic_files();
+    let (serial, serial_time) = time(false, &files);
+    let (parallel, parallel_time) = time(true, &files);
+    assert_eq!(serial, parallel, "parallel counts differ from serial");
+
+    println!("{} files, {} tokens", FILES, serial.iter().sum::<usize>());
+    println!("serial:   {:?}", serial_time);
+    println!(
+        "parallel: {:?} ({:.1}x on {} threads)",
+        parallel_time,
+        serial_time.as_secs_f64() / parallel_time.as_secs_f64(),
+        rayon::current_num_threads()
+    );
+}
@@ -0,0 +1,541 @@
+// ./src/filter.rs
+use crate::patterns::relative_path;
+use ignore::WalkBuilder;
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use walkdir::WalkDir;
+
+fn is_lock_file(path: &Path) -> bool {
+    if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+        return name.ends_with(".lock")
+            || name == "Cargo.lock"
+            || name == "package-lock.json"
+            || name == "yarn.lock"
+            || name == "Pipfile.lock"
+            || name == "poetry.lock";
+    }
+    false
+}
+
+/// Tool-specific ignore file, using gitignore syntax, honored in every walked
+/// directory on top of the standard filters.
+pub const IGNORE_FILENAME: &str = ".create-context-ignore";
+
+/// Vendored dependency and build output directories, skipped unless
+/// `WalkOptions::no_default_excludes` is set.
+pub const DEFAULT_EXCLUDE_DIRS: &[&str] =
+    &["node_modules", "target", "dist", "build", ".venv", "vendor"];
+
+fn is_default_excluded_dir(name: &std::ffi::OsStr) -> bool {
+    name.to_str()
+        .is_some_and(|n| DEFAULT_EXCLUDE_DIRS.contains(&n))
+}
+
+/// Options shared by every directory walk.
+#[derive(Clone, Debug, Default)]
+pub struct WalkOptions {
+    pub include_hidden: bool,
+    /// Hidden file and directory names to walk even without `include_hidden`,
+    /// such as `.github`.
+    pub allow_dot: Vec<String>,
+    pub follow_links: bool,
+    /// Descend at most this many levels; `Some(1)` stays in the root.
+    pub max_depth: Option<usize>,
+    /// Walk into `DEFAULT_EXCLUDE_DIRS` too.
+    pub no_default_excludes: bool,
+    /// Extra ignore files in gitignore syntax, such as `.dockerignore`,
+    /// applied on top of the standard ones.
+    pub ignore_files: Vec<PathBuf>,
+    /// Skip the user's global gitignore (`core.excludesFile`, by default
+    /// `~/.config/git/ignore`).
+    pub no_global_gitignore: bool,
+}
+
+/// Creates a walker with the standard ignore filters plus `IGNORE_FILENAME`.
+/// Inside a git repository these include `.git/info/exclude` and the global
+/// gitignore.
+pub fn walk_builder(root: &Path, options: &WalkOptions) -> WalkBuilder {
+    // With names to allow, hidden entries are filtered here instead of by
+    // the walker, which can only skip all of them
+    let filter_hidden = !options.include_hidden && !options.allow_dot.is_empty();
+    let default_excludes = !options.no_default_excludes;
+    let mut builder = WalkBuilder::new(root);
+    builder
+        .standard_filters(true)
+        .git_global(!options.no_global_gitignore)
+        .git_exclude(true)
+        .hidden(!options.include_hidden && !filter_hidden)
+        .follow_links(options.follow_links)
+        .max_depth(options.max_depth)
+        .add_custom_ignore_filename(IGNORE_FILENAME);
+    for path in &options.ignore_files {
+        if let Some(e) = builder.add_ignore(path) {
+            warn!(
+                "Warning: failed to read ignore file '{}': {}",
+                path.display(),
+                e
+            );
+        }
+    }
+    if filter_hidden || default_excludes {
+        let allow_dot = options.allow_dot.clone();
+        // The root itself is always walked, even if it is e.g. `vendor`
+        builder.filter_entry(move |entry| {
+            if entry.depth() == 0 {
+                return true;
+            }
+            let name = entry.file_name().to_str().unwrap_or("");
+            if filter_hidden && name.starts_with('.') && !allow_dot.iter().any(|a| a == name) {
+                return false;
+            }
+            !(default_excludes
+                && entry.file_type().is_some_and(|ft| ft.is_dir())
+                && is_default_excluded_dir(entry.file_name()))
+        });
+    }
+    builder
+}
+
+/// Options controlling which paths `is_excluded` rejects.
+#[derive(Clone, Debug, Default)]
+pub struct FilterOptions {
+    pub include_hidden: bool,
+    pub allow_dot: Vec<String>,
+    pub include_lockfiles: bool,
+    pub no_default_excludes: bool,
+    /// The ignore rules for the walk root, built once up front.
+    pub ignore: IgnoreMatcher,
+}
+
+impl FilterOptions {
+    /// Builds the filters for paths under `base_dir`. This walks `base_dir`
+    /// once to index which paths survive the ignore rules.
+    pub fn new(base_dir: &Path, walk: WalkOptions, include_lockfiles: bool) -> Self {
+        FilterOptions {
+            include_hidden: walk.include_hidden,
+            allow_dot: walk.allow_dot.clone(),
+            include_lockfiles,
+            no_default_excludes: walk.no_default_excludes,
+            ignore: IgnoreMatcher::build(base_dir, &walk),
+        }
+    }
+
+    /// Lists the files and directories under `base_dir` that the ignore-aware
+    /// walk skips without yielding, with the reason for each. The contents of
+    /// a skipped directory are not listed. This walks `base_dir` again, so it
+    /// is only meant for diagnostics.
+    pub fn pruned_paths(
+        &self,
+        base_dir: &Path,
+        walk: &WalkOptions,
+    ) -> Vec<(PathBuf, &'static str)> {
+        let mut pruned = Vec::new();
+        let mut entries = WalkDir::new(base_dir)
+            .follow_links(walk.follow_links)
+            .min_depth(1)
+            .max_depth(walk.max_depth.unwrap_or(usize::MAX))
+            .into_iter();
+        while let Some(entry) = entries.next() {
+            let Ok(entry) = entry else {
+                continue;
+            };
+            if !self.ignore.is_ignored(entry.path()) {
+                continue;
+            }
+            let is_dir = entry.file_type().is_dir();
+            let reason = if is_dir
+                && !walk.no_default_excludes
+                && is_default_excluded_dir(entry.file_name())
+            {
+                "vendored or build directory"
+            } else {
+                exclusion_reason(entry.path(), base_dir, self)
+                    .unwrap_or("ignored by .gitignore or .create-context-ignore")
+            };
+            pruned.push((entry.path().to_path_buf(), reason));
+            if is_dir {
+                entries.skip_current_dir();
+            }
+        }
+        pruned
+    }
+}
+
+/// The set of paths under a root that survive the standard ignore filters.
+/// Built with a single walk so that checking a path is a lookup rather than
+/// a fresh walk of its parent directory.
+#[derive(Clone, Debug, Default)]
+pub struct IgnoreMatcher {
+    root: PathBuf,
+    allowed: HashSet<PathBuf>,
+}
+
+impl IgnoreMatcher {
+    /// Walks `base_dir` once with the standard filters and records every
+    /// path that survives.
+    pub fn build(base_dir: &Path, walk: &WalkOptions) -> Self {
+        let allowed = walk_builder(base_dir, walk)
+            .build()
+            .flatten()
+            .filter_map(|entry| entry.path().canonicalize().ok());
+        IgnoreMatcher::from_allowed(base_dir, allowed)
+    }
+
+    /// A matcher that keeps exactly the `allowed` paths under `root`, for
+    /// callers that already know what survives the ignore rules.
+    pub fn from_allowed(root: &Path, allowed: impl IntoIterator<Item = PathBuf>) -> Self {
+        IgnoreMatcher {
+            root: root.canonicalize().unwrap_or_else(|_| root.to_path_buf()),
+            allowed: allowed.into_iter().collect(),
+        }
+    }
+
+    /// The canonical root the matcher was built for.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns true if `path` is under the root but was not kept. Paths
+    /// outside the root, such as explicitly listed files elsewhere, are never
+    /// ignored.
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        path.starts_with(&self.root) && !self.allowed.contains(&path)
+    }
+}
+
+/// Returns why `path` should be left out, or `None` to keep it: lock files,
+/// hidden or `.git` paths, vendored or build directories, and anything the
+/// ignore rules reject.
+pub fn exclusion_reason(
+    path: &Path,
+    base_dir: &Path,
+    filters: &FilterOptions,
+) -> Option<&'static str> {
+    exclusion_reason_with(path, base_dir, filters, false)
+}
+
+/// Like `exclusion_reason`, for a file the user named explicitly: hidden
+/// paths such as `.env.example` are kept. `.git` still is not.
+pub fn explicit_exclusion_reason(
+    path: &Path,
+    base_dir: &Path,
+    filters: &FilterOptions,
+) -> Option<&'static str> {
+    exclusion_reason_with(path, base_dir, filters, true)
+}
+
+fn exclusion_reason_with(
+    path: &Path,
+    base_dir: &Path,
+    filters: &FilterOptions,
+    allow_hidden: bool,
+) -> Option<&'static str> {
+    if !filters.include_lockfiles && is_lock_file(path) {
+        return Some("lock file");
+    }
+
+    // Only look below `base_dir`: a project may itself live under a hidden
+    // directory such as `~/.cache`
+    let relative = relative_path(path, base_dir, filters.ignore.root());
+    let mut hidden = false;
+    for component in relative.components() {
+        if let Component::Normal(part) = component {
+            if let Some(part_str) = part.to_str() {
+                // The git directory is never useful context, even with hidden files enabled
+                if part_str == ".git" {
+                    return Some("git directory");
+                }
+                hidden |= !filters.include_hidden
+                    && part_str.starts_with('.')
+                    && !filters.allow_dot.iter().any(|a| a == part_str);
+            }
+        }
+    }
+    if hidden && !allow_hidden {
+        return Some("hidden component");
+    }
+    if !filters.no_default_excludes {
+        let mut dirs = relative.parent().into_iter().flat_map(Path::components);
+        if dirs.any(|c| matches!(c, Component::Normal(part) if is_default_excluded_dir(part))) {
+            return Some("vendored or build directory");
+        }
+    }
+
+    // The walk behind the matcher skipped hidden paths, so it knows nothing
+    // about them
+    (!hidden && filters.ignore.is_ignored(path))
+        .then_some("ignored by .gitignore or .create-context-ignore")
+}
+
+/// Returns true if `path` should be left out; see `exclusion_reason`.
+pub fn is_excluded(path: &Path, base_dir: &Path, filters: &FilterOptions) -> bool {
+    exclusion_reason(path, base_dir, filters).is_some()
+}
+
+/// Directory names whose contents are treated as tests.
+const TEST_DIRS: &[&str] = &["tests", "test", "__tests__"];
+
+/// Determines if a given path corresponds to a test file.
+/// This checks for:
+/// - Any file inside a directory named `tests`, `test` or `__tests__`, or
+///   one of `extra_dirs`
+/// - Rust filenames ending with `_test.rs` or equal to `tests.rs`
+/// - Python filenames matching `test_*.py` or `*_test.py`
+/// - JavaScript/TypeScript filenames matching `*.test.*` or `*.spec.*`
+/// - Go filenames ending with `_test.go`
+/// - Filenames ending with one of `extra_suffixes`, such as `_spec.rs`
+pub fn is_test_file(path: &Path, extra_dirs: &[String], extra_suffixes: &[String]) -> bool {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let is_test_name = match path.extension().and_then(|e| e.to_str()) {
+        Some("rs") => stem.ends_with("_test") || stem == "tests",
+        Some("py") => stem.starts_with("test_") || stem.ends_with("_test"),
+        Some("js" | "jsx" | "mjs" | "cjs" | "ts" | "tsx") => {
+            stem.ends_with(".test") || stem.ends_with(".spec")
+        }
+        Some("go") => stem.ends_with("_test"),
+        _ => false,
+    };
+    if is_test_name || extra_suffixes.iter().any(|s| name.ends_with(s.as_str())) {
+        return true;
+    }
+
+    if let Some(parent) = path.parent() {
+        for component in parent.components() {
+            if let Component::Normal(part) = component {
+                if part
+                    .to_str()
+                    .is_some_and(|p| TEST_DIRS.contains(&p) || extra_dirs.iter().any(|d| d == p))
+                {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_follow_links_toggle() {
+        let tmp = tempfile::tempdir().unwrap();
+        let real = tmp.path().join("real");
+        fs::create_dir(&real).unwrap();
+        fs::write(real.join("lib.rs"), "fn f() {}\n").unwrap();
+        let root = tmp.path().join("root");
+        fs::create_dir(&root).unwrap();
+        std::os::unix::fs::symlink(&real, root.join("linked")).unwrap();
+
+        let walk_files = |follow_links| -> Vec<PathBuf> {
+            let options = WalkOptions {
+                follow_links,
+                ..WalkOptions::default()
+            };
+            walk_builder(&root, &options)
+                .build()
+                .flatten()
+                .filter(|e| e.file_type().is_some_and(|ft| ft.is_file()))
+                .map(|e| e.into_path())
+                .collect()
+        };
+
+        assert!(walk_files(false).is_empty());
+        assert_eq!(walk_files(true), vec![root.join("linked").join("lib.rs")]);
+    }
+
+    #[test]
+    fn test_is_test_file_extra_names() {
+        let dirs = ["it".to_string()];
+        let suffixes = ["_spec.rs".to_string()];
+        assert!(is_test_file(Path::new("src/lib_test.rs"), &dirs, &suffixes));
+        assert!(is_test_file(Path::new("it/smoke.rs"), &dirs, &suffixes));
+        assert!(is_test_file(
+            Path::new("src/parser_spec.rs"),
+            &dirs,
+            &suffixes
+        ));
+        assert!(!is_test_file(Path::new("it/smoke.rs"), &[], &[]));
+        assert!(!is_test_file(Path::new("src/parser_spec.rs"), &[], &[]));
+        assert!(!is_test_file(Path::new("src/split.rs"), &dirs, &suffixes));
+    }
+
+    #[test]
+    fn test_extra_ignore_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join(".dockerignore"), "*.log\n").unwrap();
+        fs::write(root.join("main.rs"), "").unwrap();
+        fs::write(root.join("debug.log"), "").unwrap();
+        let walk = WalkOptions {
+            ignore_files: vec![root.join(".dockerignore")],
+            ..WalkOptions::default()
+        };
+        let filters = FilterOptions::new(root, walk, false);
+        assert!(!is_excluded(&root.join("main.rs"), root, &filters));
+        assert!(is_excluded(&root.join("debug.log"), root, &filters));
+    }
+
+    #[test]
+    fn test_allow_dot() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        for dir in [".github", ".cache"] {
+            fs::create_dir(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("ci.yml"), "").unwrap();
+        }
+        let walk = WalkOptions {
+            allow_dot: vec![".github".to_string()],
+            ..WalkOptions::default()
+        };
+        let walked: Vec<PathBuf> = walk_builder(root, &walk)
+            .build()
+            .flatten()
+            .filter(|e| e.file_type().is_some_and(|ft| ft.is_file()))
+            .map(|e| e.into_path())
+            .collect();
+        assert_eq!(walked, [root.join(".github").join("ci.yml")]);
+
+        let filters = FilterOptions::new(root, walk, false);
+        assert!(!is_excluded(
+            &root.join(".github").join("ci.yml"),
+            root,
+            &filters
+        ));
+        assert_eq!(
+            exclusion_reason(&root.join(".cache").join("ci.yml"), root, &filters),
+            Some("hidden component")
+        );
+    }
+
+    #[test]
+    fn test_is_excluded_with_fixture_matcher() {
+        let root = Path::new("/repo");
+        let allowed = ["src/main.rs", "Cargo.lock", ".env", ".git/config"];
+        let filters = |include_hidden, include_lockfiles| FilterOptions {
+            include_hidden,
+            include_lockfiles,
+            ignore: IgnoreMatcher::from_allowed(root, allowed.iter().map(|p| root.join(p))),
+            ..FilterOptions::default()
+        };
+        let default = filters(false, false);
+        let excluded = |p: &str, filters: &FilterOptions| is_excluded(&root.join(p), root, filters);
+
+        assert!(!excluded("src/main.rs", &default));
+        assert!(excluded("build/out.rs", &default));
+        assert!(excluded("Cargo.lock", &default));
+        assert!(!excluded("Cargo.lock", &filters(false, true)));
+        assert!(excluded(".env", &default));
+        assert!(!excluded(".env", &filters(true, false)));
+        // The git directory stays out even with hidden files included
+        assert!(excluded(".git/config", &filters(true, false)));
+        // Paths outside the root are not subject to its ignore rules
+        assert!(!is_excluded(Path::new("/elsewhere/lib.rs"), root, &default));
+    }
+
+    #[test]
+    fn test_default_excludes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir_all(root.join("node_modules").join("left-pad")).unwrap();
+        fs::write(
+            root.join("node_modules").join("left-pad").join("index.js"),
+            "",
+        )
+        .unwrap();
+        fs::create_dir(root.join("src")).unwrap();
+        // Only directories are matched, not files of the same name
+        fs::write(root.join("src").join("build"), "").unwrap();
+        let vendored = root.join("node_modules").join("left-pad").join("index.js");
+
+        let filters = FilterOptions::new(root, WalkOptions::default(), false);
+        assert_eq!(
+            exclusion_reason(&vendored, root, &filters),
+            Some("vendored or build directory")
+        );
+        assert!(!is_excluded(
+            &root.join("src").join("build"),
+            root,
+            &filters
+        ));
+
+        let walk = WalkOptions {
+            no_default_excludes: true,
+            ..WalkOptions::default()
+        };
+        let filters = FilterOptions::new(root, walk, false);
+        assert!(!is_excluded(&vendored, root, &filters));
+    }
+
+    #[test]
+    fn test_explicit_hidden_files() {
+        let root = Path::new("/repo");
+        let filters = FilterOptions {
+            ignore: IgnoreMatcher::from_allowed(root, []),
+            ..FilterOptions::default()
+        };
+        let env = root.join(".env.example");
+        assert_eq!(
+            exclusion_reason(&env, root, &filters),
+            Some("hidden component")
+        );
+        assert_eq!(explicit_exclusion_reason(&env, root, &filters), None);
+        assert_eq!(
+            explicit_exclusion_reason(&root.join(".git").join("config"), root, &filters),
+            Some("git directory")
+        );
+    }
+
+    #[test]
+    fn test_project_under_hidden_directory_fixture() {
+        let root = Path::new("/home/me/.cache/project");
+        let filters = FilterOptions {
+            ignore: IgnoreMatcher::from_allowed(root, [root.join("lib.rs")]),
+            ..FilterOptions::default()
+        };
+        assert!(!is_excluded(&root.join("lib.rs"), root, &filters));
+    }
+
+    #[test]
+    fn test_pruned_paths_reasons() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir(root.join(".cache")).unwrap();
+        fs::write(root.join(".cache").join("x.rs"), "").unwrap();
+        fs::write(root.join(IGNORE_FILENAME), "generated.rs\n").unwrap();
+        fs::write(root.join("generated.rs"), "").unwrap();
+        fs::write(root.join("main.rs"), "").unwrap();
+        fs::write(root.join("Cargo.lock"), "").unwrap();
+        fs::create_dir(root.join("node_modules")).unwrap();
+
+        let filters = FilterOptions::new(root, WalkOptions::default(), false);
+        let mut pruned = filters.pruned_paths(root, &WalkOptions::default());
+        pruned.sort();
+        assert_eq!(
+            pruned,
+            [
+                (root.join(".cache"), "hidden component"),
+                (root.join(IGNORE_FILENAME), "hidden component"),
+                (
+                    root.join("generated.rs"),
+                    "ignored by .gitignore or .create-context-ignore"
+                ),
+                (root.join("node_modules"), "vendored or build directory"),
+            ]
+        );
+        // Walked files are checked one by one instead
+        assert_eq!(
+            exclusion_reason(&root.join("Cargo.lock"), root, &filters),
+            Some("lock file")
+        );
+        assert_eq!(
+            exclusion_reason(&root.join("main.rs"), root, &filters),
+            None
+        );
+    }
+}
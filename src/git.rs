@@ -0,0 +1,134 @@
+// ./src/git.rs
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Runs git in `dir` and returns its stdout.
+fn git(dir: &Path, args: &[&str]) -> io::Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to run git: {}", e)))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "git {} failed in '{}': {}",
+            args.join(" "),
+            dir.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Returns the canonical root of the repository containing `dir`.
+fn repo_root(dir: &Path) -> io::Result<PathBuf> {
+    let root = git(dir, &["rev-parse", "--show-toplevel"])?;
+    Path::new(root.trim()).canonicalize()
+}
+
+/// Returns the canonical paths of files that differ from `since` in the
+/// repository containing `dir`: committed, staged and unstaged changes, plus
+/// untracked files that are not ignored. Deleted files are left out.
+pub fn changed_files(dir: &Path, since: &str) -> io::Result<HashSet<PathBuf>> {
+    let root = repo_root(dir)?;
+    let diff = git(&root, &["diff", "--name-only", "-z", since, "--"])?;
+    let untracked = git(&root, &["ls-files", "--others", "--exclude-standard", "-z"])?;
+    Ok(diff
+        .split('\0')
+        .chain(untracked.split('\0'))
+        .filter(|path| !path.is_empty())
+        .filter_map(|path| root.join(path).canonicalize().ok())
+        .collect())
+}
+
+/// Describes a two-letter `git status --porcelain` code, or `None` for a
+/// clean file.
+fn describe_status(code: &str) -> Option<&'static str> {
+    let mut chars = code.chars();
+    let (index, worktree) = (chars.next()?, chars.next()?);
+    match (index, worktree) {
+        ('?', '?') => Some("untracked"),
+        (' ', ' ') => None,
+        (' ', _) => Some("modified"),
+        (_, ' ') => Some("staged"),
+        _ => Some("staged, modified"),
+    }
+}
+
+/// Parses `git status --porcelain -z` output into paths relative to the
+/// repository root and their described state.
+fn parse_porcelain(output: &str) -> Vec<(&str, &'static str)> {
+    let mut entries = Vec::new();
+    let mut fields = output.split('\0');
+    while let Some(entry) = fields.next() {
+        if entry.len() < 4 {
+            continue;
+        }
+        let (code, path) = (&entry[..2], &entry[3..]);
+        // Renames and copies are followed by the original path
+        if code.starts_with(['R', 'C']) {
+            fields.next();
+        }
+        if let Some(state) = describe_status(code) {
+            entries.push((path, state));
+        }
+    }
+    entries
+}
+
+/// The working tree state of every changed file in a repository.
+#[derive(Clone, Debug, Default)]
+pub struct GitStatus {
+    states: HashMap<PathBuf, &'static str>,
+}
+
+impl GitStatus {
+    /// Reads the status of the repository containing `dir`.
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let root = repo_root(dir)?;
+        let output = git(
+            &root,
+            &["status", "--porcelain", "-z", "--untracked-files=all"],
+        )?;
+        let states = parse_porcelain(&output)
+            .into_iter()
+            .map(|(path, state)| (root.join(path), state))
+            .collect();
+        Ok(GitStatus { states })
+    }
+
+    /// Adds the states read from another repository, for multiple roots.
+    pub fn merge(&mut self, other: GitStatus) {
+        self.states.extend(other.states);
+    }
+
+    /// Returns the state of `path` (`modified`, `staged`, `untracked`, ...),
+    /// or `None` if it is clean.
+    pub fn state(&self, path: &Path) -> Option<&'static str> {
+        let canonical = path.canonicalize().ok()?;
+        self.states.get(&canonical).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_porcelain() {
+        let output = " M src/main.rs\0M  README.md\0MM lib.rs\0?? new file.rs\0R  b.rs\0a.rs\0";
+        assert_eq!(
+            parse_porcelain(output),
+            [
+                ("src/main.rs", "modified"),
+                ("README.md", "staged"),
+                ("lib.rs", "staged, modified"),
+                ("new file.rs", "untracked"),
+                ("b.rs", "staged"),
+            ]
+        );
+    }
+}
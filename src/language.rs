@@ -0,0 +1,241 @@
+// ./src/language.rs
+use std::collections::HashMap;
+use std::path::Path;
+
+/// User-supplied languages for file names and extensions, checked before the
+/// built-in tables.
+#[derive(Clone, Debug, Default)]
+pub struct LanguageMap {
+    filenames: HashMap<String, String>,
+    extensions: HashMap<String, String>,
+}
+
+impl LanguageMap {
+    /// Maps `key` to `language`. A key starting with `.` is an extension
+    /// (`.pyi`); any other key is an exact file name (`SConstruct`).
+    pub fn insert(&mut self, key: &str, language: &str) {
+        match key.strip_prefix('.') {
+            Some(ext) => self
+                .extensions
+                .insert(ext.to_string(), language.to_string()),
+            None => self.filenames.insert(key.to_string(), language.to_string()),
+        };
+    }
+
+    fn get(&self, path: &Path) -> Option<&str> {
+        let file_name = path.file_name().and_then(|f| f.to_str());
+        let ext = path.extension().and_then(|e| e.to_str());
+        file_name
+            .and_then(|name| self.filenames.get(name))
+            .or_else(|| ext.and_then(|ext| self.extensions.get(ext)))
+            .map(String::as_str)
+    }
+}
+
+impl<K: AsRef<str>, L: AsRef<str>> FromIterator<(K, L)> for LanguageMap {
+    fn from_iter<I: IntoIterator<Item = (K, L)>>(iter: I) -> Self {
+        let mut map = LanguageMap::default();
+        for (key, language) in iter {
+            map.insert(key.as_ref(), language.as_ref());
+        }
+        map
+    }
+}
+
+/// Maps the interpreter named on a `#!` first line to a language, e.g.
+/// `#!/usr/bin/env python3` or `#!/bin/bash -e`.
+fn shebang_language(content: &str) -> Option<&'static str> {
+    let line = content.lines().next()?.strip_prefix("#!")?;
+    let mut words = line.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = words.find(|w| !w.starts_with('-') && !w.contains('='))?;
+    }
+    // Drop version suffixes such as `python3.12`
+    match program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.') {
+        "python" => Some("python"),
+        "sh" | "bash" | "dash" | "zsh" | "ksh" => Some("bash"),
+        "node" | "nodejs" => Some("javascript"),
+        "ruby" => Some("ruby"),
+        "perl" => Some("perl"),
+        _ => None,
+    }
+}
+
+/// Guesses the language of a file from its name or extension, for use as the
+/// code fence info string. Unknown files get an empty string.
+pub fn determine_language(file_path: &str) -> String {
+    determine_language_with(file_path, None, &LanguageMap::default())
+}
+
+/// Like `determine_language`, but `overrides` take precedence over the
+/// built-in mappings, and if the name gives nothing away the file's already
+/// read `content` is checked for a shebang line.
+pub fn determine_language_with(
+    file_path: &str,
+    content: Option<&str>,
+    overrides: &LanguageMap,
+) -> String {
+    if let Some(lang) = overrides.get(Path::new(file_path)) {
+        return lang.to_string();
+    }
+
+    let filename_to_language: HashMap<&str, &str> = HashMap::from([
+        ("Makefile", "make"),
+        ("CMakeLists.txt", "cmake"),
+        ("Dockerfile", "docker"),
+        (".gitignore", "git"),
+        ("build.gradle", "gradle"),
+        ("Cargo.toml", "rust"),
+        ("package.json", "node"),
+    ]);
+
+    let extension_to_language: HashMap<&str, &str> = HashMap::from([
+        ("rs", "rust"),
+        ("zig", "zig"),
+        ("zon", "zig"),
+        ("go", "go"),
+        ("py", "python"),
+        ("cpp", "cpp"),
+        ("cc", "cpp"),
+        ("cxx", "cpp"),
+        ("hpp", "cpp"),
+        ("hh", "cpp"),
+        ("hxx", "cpp"),
+        ("c", "c"),
+        ("h", "c"),
+        ("cu", "cuda"),
+        ("cuh", "cuda"),
+        ("js", "javascript"),
+        ("ts", "typescript"),
+        ("toml", "toml"),
+        ("yaml", "yaml"),
+        ("yml", "yaml"),
+        ("json", "json"),
+        ("txt", "txt"),
+        ("sh", "bash"),
+        ("md", "markdown"),
+        ("proto", "protobuf"),
+        ("cmake", "cmake"),
+        ("html", "html"),
+        ("css", "css"),
+        ("rb", "ruby"),
+        ("php", "php"),
+        ("sql", "sql"),
+        ("r", "r"),
+        ("R", "r"),
+        ("hs", "haskell"),
+        ("ml", "ocaml"),
+        ("mli", "ocaml"),
+        ("ex", "elixir"),
+        ("exs", "elixir"),
+        ("tf", "terraform"),
+        ("tfvars", "terraform"),
+    ]);
+
+    let path = Path::new(file_path);
+
+    if let Some(file_name) = path.file_name().and_then(|f| f.to_str()) {
+        if let Some(lang) = filename_to_language.get(file_name) {
+            return lang.to_string();
+        }
+    }
+
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        if let Some(lang) = extension_to_language.get(ext) {
+            return lang.to_string();
+        }
+    }
+
+    content
+        .and_then(shebang_language)
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// The comment markers used for the header line naming each file: a start
+/// marker and, for block-only languages, an end marker. `None` for languages
+/// with no comment syntax, such as JSON, where any header would make the
+/// block invalid.
+pub fn comment_syntax(language: &str) -> Option<(&'static str, Option<&'static str>)> {
+    let syntax = match language {
+        "rust" | "cpp" | "c" | "go" | "javascript" | "typescript" | "java" | "swift" | "kotlin" => {
+            ("//", None)
+        }
+        "python" | "bash" | "sh" | "yaml" | "yml" | "toml" | "make" => ("#", None),
+        "ruby" | "perl" | "r" | "elixir" | "terraform" => ("#", None),
+        "lua" | "sql" | "haskell" => ("--", None),
+        "ocaml" => ("(*", Some("*)")),
+        "php" => ("//", None),
+        "html" | "xml" => ("<!--", Some("-->")),
+        "css" | "scss" => ("/*", Some("*/")),
+        "protobuf" => ("//", None),
+        // `node` is package.json
+        "json" | "node" => return None,
+        // Base64 blocks from `--include-binary-as-base64`
+        "base64" => return None,
+        "markdown" => ("<!--", Some("-->")),
+        _ => ("//", None),
+    };
+    Some(syntax)
+}
+
+/// The MIME type for a binary file, guessed from its extension, for the note
+/// on base64-encoded files.
+pub(crate) fn mime_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    match extension.to_ascii_lowercase().as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "bmp" => "image/bmp",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "der" | "cer" | "crt" => "application/pkix-cert",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "wasm" => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Line and block comment markers recognized when stripping comments.
+/// Unknown languages have no markers, so their content is left untouched.
+pub(crate) fn comment_markers(
+    language: &str,
+) -> (Option<&'static str>, Option<(&'static str, &'static str)>) {
+    match language {
+        "rust" | "cpp" | "c" | "cuda" | "go" | "javascript" | "typescript" | "java" | "swift"
+        | "kotlin" | "php" | "protobuf" | "scss" => (Some("//"), Some(("/*", "*/"))),
+        "zig" => (Some("//"), None),
+        "python" | "bash" | "sh" | "yaml" | "yml" | "toml" | "make" | "ruby" | "perl" | "r"
+        | "elixir" | "terraform" => (Some("#"), None),
+        "sql" => (Some("--"), Some(("/*", "*/"))),
+        "haskell" => (Some("--"), Some(("{-", "-}"))),
+        "lua" => (Some("--"), None),
+        "css" => (None, Some(("/*", "*/"))),
+        "html" | "xml" | "markdown" => (None, Some(("<!--", "-->"))),
+        "ocaml" => (None, Some(("(*", "*)"))),
+        _ => (None, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shebang_language() {
+        assert_eq!(shebang_language("#!/usr/bin/env python3\n"), Some("python"));
+        assert_eq!(shebang_language("#!/usr/bin/python3.12"), Some("python"));
+        assert_eq!(shebang_language("#!/bin/bash -e\necho"), Some("bash"));
+        assert_eq!(
+            shebang_language("#!/usr/bin/env -S node --harmony"),
+            Some("javascript")
+        );
+        assert_eq!(shebang_language("#!/usr/bin/perl -w"), Some("perl"));
+        assert_eq!(shebang_language("#!/usr/bin/env awk"), None);
+        assert_eq!(shebang_language("print('hi')"), None);
+    }
+}
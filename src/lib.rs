@@ -0,0 +1,656 @@
+// ./src/lib.rs
+//! Collects source files under a directory and renders them into a single
+//! document suitable for pasting into a language model's context.
+//!
+//! ```no_run
+//! use create_context::{collect_files, render_context, Config, ContextOptions};
+//!
+//! let config = Config {
+//!     patterns: vec!["**/*.rs".to_string()],
+//!     ..Config::default()
+//! };
+//! let files = collect_files(&config)?;
+//! let context = render_context(&files, &ContextOptions::default())?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+/// Prints a non-fatal message (a warning or a skipped file) to stderr, unless
+/// quiet mode is on.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if !$crate::is_quiet() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+mod filter;
+mod git;
+mod language;
+mod patterns;
+mod process;
+mod render;
+mod tokens;
+mod tree;
+
+pub use filter::{
+    exclusion_reason, explicit_exclusion_reason, is_excluded, is_test_file, walk_builder,
+    FilterOptions, IgnoreMatcher, WalkOptions, IGNORE_FILENAME,
+};
+pub use git::{changed_files, GitStatus};
+pub use language::{comment_syntax, determine_language, determine_language_with, LanguageMap};
+pub use patterns::{check_patterns, compile_patterns, path_matches, relative_path, PatternSet};
+pub use process::{
+    process_file, process_files, try_process_file, try_process_files, ProcessOptions, ProcessedFile,
+};
+pub use render::{
+    render_chunks, render_files, render_jsonl_line, render_markdown, render_xml_document,
+    table_of_contents, Format, RenderOptions, JSON_SCHEMA,
+};
+pub use tokens::{
+    apply_token_budget, count_tokens, count_tokens_chunked, file_token_counts, file_tokens,
+    flag_large_files, load_tokenizer, truncate_file, truncate_files, Tokenizer, TRUNCATED_MARKER,
+};
+pub use tree::{render_tree, TreeContext, TreeStyle, ASCII_TREE, UNICODE_TREE};
+
+use clap::ValueEnum;
+use patterns::{compile_excludes, is_excluded_by};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Silences the warnings and notes the library prints to stderr. Errors are
+/// still returned as usual.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Whether `set_quiet(true)` is in effect.
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Orderings for the included files
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SortKey {
+    /// Alphabetically by path
+    #[default]
+    Path,
+    /// Smallest file first
+    Size,
+    /// Least recently modified first
+    Mtime,
+}
+
+/// Which files to collect and how to order them.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Directory to walk; explicit files are resolved relative to it.
+    pub dir: PathBuf,
+    /// Glob patterns a walked file must match.
+    pub patterns: Vec<String>,
+    /// Specific files to include, on top of any matched by `patterns`.
+    pub files: Vec<String>,
+    /// Glob patterns to exclude, applied after everything else. A leading `!`
+    /// re-includes paths excluded by an earlier pattern.
+    pub exclude: Vec<String>,
+    pub ignore_tests: bool,
+    /// Directory names whose contents count as tests for `ignore_tests`, on
+    /// top of `tests`, `test` and `__tests__`.
+    pub test_dirs: Vec<String>,
+    /// File name endings, such as `_spec.rs`, that mark tests for
+    /// `ignore_tests` on top of the built-in ones.
+    pub test_suffixes: Vec<String>,
+    pub include_hidden: bool,
+    /// Hidden file and directory names to include anyway, e.g. `.github`.
+    pub allow_dot: Vec<String>,
+    pub include_lockfiles: bool,
+    pub follow_links: bool,
+    /// Descend at most this many levels below `dir` when walking; `Some(1)`
+    /// collects only the files directly in it.
+    pub max_depth: Option<usize>,
+    /// Walk into vendored and build directories such as `node_modules` and
+    /// `target`, which are skipped by default.
+    pub no_default_excludes: bool,
+    /// Extra ignore files in gitignore syntax, such as `.dockerignore`.
+    pub ignore_files: Vec<PathBuf>,
+    /// Skip the user's global gitignore.
+    pub no_global_gitignore: bool,
+    /// Canonicalize the collected paths instead of joining them onto `dir`.
+    pub absolute_paths: bool,
+    pub sort: SortKey,
+    /// Keep only files changed since this git ref (e.g. `main` or `HEAD~3`).
+    pub since: Option<String>,
+    /// Keep only files with one of these extensions (`rs`, `d.ts`). When
+    /// `patterns` is empty, every walked file with a listed extension is
+    /// selected.
+    pub extensions: Vec<String>,
+    /// Drop files with any of these extensions.
+    pub exclude_extensions: Vec<String>,
+    /// Fail on invalid glob patterns instead of warning and skipping them.
+    pub strict: bool,
+    /// Print each candidate file that is left out, and why, to stderr.
+    pub verbose: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            dir: PathBuf::from("."),
+            patterns: Vec::new(),
+            files: Vec::new(),
+            exclude: Vec::new(),
+            ignore_tests: false,
+            test_dirs: Vec::new(),
+            test_suffixes: Vec::new(),
+            include_hidden: false,
+            allow_dot: Vec::new(),
+            include_lockfiles: false,
+            follow_links: false,
+            max_depth: None,
+            no_default_excludes: false,
+            ignore_files: Vec::new(),
+            no_global_gitignore: false,
+            absolute_paths: false,
+            sort: SortKey::default(),
+            since: None,
+            extensions: Vec::new(),
+            exclude_extensions: Vec::new(),
+            strict: false,
+            verbose: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn walk_options(&self) -> WalkOptions {
+        WalkOptions {
+            include_hidden: self.include_hidden,
+            allow_dot: self.allow_dot.clone(),
+            follow_links: self.follow_links,
+            max_depth: self.max_depth,
+            no_default_excludes: self.no_default_excludes,
+            ignore_files: self.ignore_files.clone(),
+            no_global_gitignore: self.no_global_gitignore,
+        }
+    }
+
+    /// Builds the filters used both for collecting files and for the tree.
+    pub fn filters(&self) -> FilterOptions {
+        FilterOptions::new(&self.dir, self.walk_options(), self.include_lockfiles)
+    }
+
+    /// With `verbose`, reports a candidate that was left out and why.
+    fn log_skipped(&self, path: &Path, reason: &str) {
+        if self.verbose {
+            let relative = relative_path(path, &self.dir, &self.canonical_dir()).to_path_buf();
+            eprintln!("skipped {}: {}", relative.display(), reason);
+        }
+    }
+
+    /// `dir` in canonical form, or as given if it cannot be resolved.
+    pub fn canonical_dir(&self) -> PathBuf {
+        self.dir.canonicalize().unwrap_or_else(|_| self.dir.clone())
+    }
+}
+
+/// Returns true if the file name of `path` ends with `.ext` for one of
+/// `extensions`. A leading `.` on an extension is ignored.
+fn has_extension(path: &Path, extensions: &[String]) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    extensions.iter().any(|ext| {
+        let ext = ext.trim_start_matches('.');
+        name.strip_suffix(ext)
+            .is_some_and(|stem| stem.len() > 1 && stem.ends_with('.'))
+    })
+}
+
+/// Removes files that resolve to the same canonical path as an earlier one,
+/// e.g. a file listed twice or reached through a symlink.
+fn dedup_files(files: &mut Vec<PathBuf>) {
+    let mut seen = HashSet::new();
+    files.retain(|path| seen.insert(path.canonicalize().unwrap_or_else(|_| path.clone())));
+}
+
+/// Sorts files by the given key. Files are first ordered by path so that ties
+/// keep a stable order, and each file is stat-ed at most once.
+fn sort_files(files: &mut [PathBuf], key: SortKey) {
+    files.sort();
+    match key {
+        SortKey::Path => {}
+        SortKey::Size => {
+            files.sort_by_cached_key(|path| fs::metadata(path).map(|m| m.len()).unwrap_or(0))
+        }
+        SortKey::Mtime => {
+            files.sort_by_cached_key(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        }
+    }
+}
+
+/// Walks `config.dir` and returns every file that passes the ignore rules,
+/// before any pattern matching.
+pub fn walk_files(config: &Config, filters: &FilterOptions) -> Vec<PathBuf> {
+    let base_dir = config.dir.as_path();
+    let mut files = Vec::new();
+    for result in walk_builder(base_dir, &config.walk_options()).build() {
+        let entry = match result {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("Error reading directory entry: {}", e);
+                continue;
+            }
+        };
+
+        let path = entry.path();
+        if !entry.file_type().is_some_and(|ft| ft.is_file()) {
+            continue;
+        }
+        if let Some(reason) = exclusion_reason(path, base_dir, filters) {
+            config.log_skipped(path, reason);
+            continue;
+        }
+        if config.ignore_tests && is_test_file(path, &config.test_dirs, &config.test_suffixes) {
+            config.log_skipped(path, "test file");
+            continue;
+        }
+        files.push(path.to_path_buf());
+    }
+    files
+}
+
+/// Collects the files selected by `config`, deduplicated and sorted.
+pub fn collect_files(config: &Config) -> io::Result<Vec<PathBuf>> {
+    collect_files_with(config, &config.filters())
+}
+
+/// Like `collect_files`, reusing filters the caller has already built.
+pub fn collect_files_with(config: &Config, filters: &FilterOptions) -> io::Result<Vec<PathBuf>> {
+    Ok(collect_files_detailed(config, filters)?.files)
+}
+
+/// The result of `collect_files_detailed`.
+#[derive(Clone, Debug, Default)]
+pub struct Collected {
+    /// The selected files, deduplicated and sorted.
+    pub files: Vec<PathBuf>,
+    /// Entries of `Config::files` that were skipped because they do not
+    /// exist or are not regular files.
+    pub invalid: Vec<String>,
+}
+
+/// Like `collect_files_with`, also reporting which explicitly listed files
+/// were invalid. Each one is warned about on stderr and skipped.
+pub fn collect_files_detailed(config: &Config, filters: &FilterOptions) -> io::Result<Collected> {
+    let mut matched_files = Vec::new();
+    let mut invalid = Vec::new();
+    let base_dir = config.dir.as_path();
+    let canonical_base = config.canonical_dir();
+
+    if config.strict {
+        let excludes = config
+            .exclude
+            .iter()
+            .map(|p| p.strip_prefix('!').unwrap_or(p));
+        check_patterns(config.patterns.iter().map(String::as_str).chain(excludes))?;
+    }
+
+    for file in &config.files {
+        let full_path = match base_dir.join(file).canonicalize() {
+            Ok(path) if path.is_file() => path,
+            Ok(path) => {
+                warn!("Warning: '{}' is not a valid file.", path.display());
+                invalid.push(file.clone());
+                continue;
+            }
+            Err(e) => {
+                warn!(
+                    "Warning: '{}' is not a valid file: {}",
+                    base_dir.join(file).display(),
+                    e
+                );
+                invalid.push(file.clone());
+                continue;
+            }
+        };
+
+        if let Some(reason) = explicit_exclusion_reason(&full_path, base_dir, filters) {
+            config.log_skipped(&full_path, reason);
+            continue;
+        }
+
+        if config.ignore_tests && is_test_file(&full_path, &config.test_dirs, &config.test_suffixes)
+        {
+            config.log_skipped(&full_path, "test file");
+            continue;
+        }
+
+        // Keep explicit files in the same form as walked ones: joined onto `dir`
+        matched_files.push(base_dir.join(relative_path(&full_path, base_dir, &canonical_base)));
+    }
+
+    let patterns = compile_patterns(&config.patterns);
+    // `extensions` alone selects files just as a pattern would
+    let select_all =
+        config.patterns.is_empty() && config.files.is_empty() && !config.extensions.is_empty();
+    if !patterns.is_empty() || select_all {
+        if config.verbose {
+            for (path, reason) in filters.pruned_paths(base_dir, &config.walk_options()) {
+                config.log_skipped(&path, reason);
+            }
+        }
+        matched_files.extend(walk_files(config, filters).into_iter().filter(|path| {
+            let matched = select_all || path_matches(&patterns, path, base_dir, &canonical_base);
+            if !matched {
+                config.log_skipped(path, "does not match --patterns");
+            }
+            matched
+        }));
+    }
+
+    // Drops the files failing `keep`, reporting them with `reason`
+    let retain = |files: &mut Vec<PathBuf>, reason: &str, keep: &dyn Fn(&Path) -> bool| {
+        files.retain(|path| {
+            let kept = keep(path);
+            if !kept {
+                config.log_skipped(path, reason);
+            }
+            kept
+        });
+    };
+
+    if !config.extensions.is_empty() {
+        retain(&mut matched_files, "extension not in --ext", &|path| {
+            has_extension(path, &config.extensions)
+        });
+    }
+    if !config.exclude_extensions.is_empty() {
+        retain(&mut matched_files, "extension in --no-ext", &|path| {
+            !has_extension(path, &config.exclude_extensions)
+        });
+    }
+
+    dedup_files(&mut matched_files);
+
+    if let Some(since) = &config.since {
+        let changed = changed_files(base_dir, since)?;
+        let reason = format!("unchanged since {}", since);
+        retain(&mut matched_files, &reason, &|path| {
+            path.canonicalize().is_ok_and(|p| changed.contains(&p))
+        });
+    }
+
+    let excludes = compile_excludes(&config.exclude);
+    if !excludes.is_empty() {
+        retain(&mut matched_files, "matches --exclude", &|path| {
+            !is_excluded_by(&excludes, path, base_dir, &canonical_base)
+        });
+    }
+
+    if config.absolute_paths {
+        matched_files = matched_files
+            .into_iter()
+            .map(|path| path.canonicalize().unwrap_or(path))
+            .collect();
+    }
+
+    sort_files(&mut matched_files, config.sort);
+    Ok(Collected {
+        files: matched_files,
+        invalid,
+    })
+}
+
+/// Everything needed to turn a list of files into a context document.
+#[derive(Clone, Debug, Default)]
+pub struct ContextOptions {
+    pub process: ProcessOptions,
+    pub render: RenderOptions,
+    pub tokenizer: Tokenizer,
+    /// Read and transform files in parallel.
+    pub parallel: bool,
+}
+
+/// Reads, transforms and renders `files`. The directory tree is not
+/// included; use `render_tree` for that.
+pub fn render_context(files: &[PathBuf], options: &ContextOptions) -> io::Result<String> {
+    let processed = process_files(files, &options.process, options.parallel);
+    let bpe = matches!(options.render.format, Format::Json | Format::Jsonl)
+        .then(|| load_tokenizer(options.tokenizer))
+        .transpose()?;
+    render_files(&processed, None, &options.render, bpe.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_has_extension() {
+        let exts = ["rs".to_string(), ".d.ts".to_string()];
+        assert!(has_extension(Path::new("src/main.rs"), &exts));
+        assert!(has_extension(Path::new("types/index.d.ts"), &exts));
+        assert!(!has_extension(Path::new("index.ts"), &exts));
+        assert!(!has_extension(Path::new("src/.rs"), &exts));
+        assert!(!has_extension(Path::new("Cargo.toml"), &exts));
+    }
+
+    #[test]
+    fn test_ignore_file_negation() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join(IGNORE_FILENAME), "*.log\n!keep.log\n").unwrap();
+        for name in ["debug.log", "keep.log", "main.rs"] {
+            fs::write(root.join(name), "x\n").unwrap();
+        }
+
+        let config = Config {
+            dir: root.to_path_buf(),
+            patterns: vec!["*".to_string()],
+            ..Config::default()
+        };
+        let files = collect_files(&config).unwrap();
+        assert_eq!(files, vec![root.join("keep.log"), root.join("main.rs")]);
+    }
+
+    #[test]
+    fn test_project_under_hidden_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join(".cache").join("project");
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(root.join(".secret")).unwrap();
+        fs::write(root.join("src").join("main.rs"), "fn main() {}\n").unwrap();
+        fs::write(root.join(".secret").join("key.rs"), "const KEY: u8 = 0;\n").unwrap();
+
+        let walked = Config {
+            dir: root.clone(),
+            patterns: vec!["**/*.rs".to_string()],
+            ..Config::default()
+        };
+        assert_eq!(
+            collect_files(&walked).unwrap(),
+            vec![root.join("src").join("main.rs")]
+        );
+
+        // Explicit files are canonicalized, so their full path includes
+        // `.cache`. Hidden files are kept when named explicitly.
+        let explicit = Config {
+            dir: root.clone(),
+            files: vec!["src/main.rs".to_string(), ".secret/key.rs".to_string()],
+            ..Config::default()
+        };
+        assert_eq!(
+            collect_files(&explicit).unwrap(),
+            vec![
+                root.join(".secret").join("key.rs"),
+                root.join("src").join("main.rs")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_depth_stays_in_root() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir(root.join("src")).unwrap();
+        fs::write(root.join("Cargo.toml"), "[package]\n").unwrap();
+        fs::write(root.join("src").join("main.rs"), "fn main() {}\n").unwrap();
+
+        let config = Config {
+            dir: root.to_path_buf(),
+            patterns: vec!["**/*".to_string()],
+            max_depth: Some(1),
+            ..Config::default()
+        };
+        assert_eq!(
+            collect_files(&config).unwrap(),
+            vec![root.join("Cargo.toml")]
+        );
+    }
+
+    #[test]
+    fn test_patterns_and_files_union() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("main.rs"), "fn main() {}\n").unwrap();
+        fs::write(root.join("lib.rs"), "pub fn f() {}\n").unwrap();
+        fs::write(root.join("Cargo.toml"), "[package]\n").unwrap();
+
+        let config = Config {
+            dir: root.to_path_buf(),
+            patterns: vec!["*.rs".to_string()],
+            files: vec!["Cargo.toml".to_string(), "main.rs".to_string()],
+            ..Config::default()
+        };
+        assert_eq!(
+            collect_files(&config).unwrap(),
+            vec![
+                root.join("Cargo.toml"),
+                root.join("lib.rs"),
+                root.join("main.rs")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_missing_files_are_skipped() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("main.rs"), "fn main() {}\n").unwrap();
+        fs::write(root.join("lib.rs"), "pub fn f() {}\n").unwrap();
+
+        let config = Config {
+            dir: root.to_path_buf(),
+            files: vec![
+                "main.rs".to_string(),
+                "lib.rs".to_string(),
+                "gone.rs".to_string(),
+            ],
+            ..Config::default()
+        };
+        let collected = collect_files_detailed(&config, &config.filters()).unwrap();
+        assert_eq!(collected.invalid, ["gone.rs"]);
+
+        // Deleted after the walk but before being read
+        fs::remove_file(root.join("lib.rs")).unwrap();
+        for options in [
+            ProcessOptions::default(),
+            ProcessOptions {
+                max_file_size: Some(1024),
+                ..ProcessOptions::default()
+            },
+        ] {
+            let processed = process_files(&collected.files, &options, false);
+            assert_eq!(processed.len(), 1);
+            assert!(processed[0].content.contains("fn main"));
+        }
+    }
+
+    #[test]
+    fn test_parallel_output_matches_serial() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        for dir in 0..8 {
+            let dir_path = root.join(format!("mod{dir}"));
+            fs::create_dir_all(&dir_path).unwrap();
+            for file in 0..16 {
+                let body = format!("// file {file}\nfn f{dir}_{file}() {{}}\n").repeat(file + 1);
+                fs::write(dir_path.join(format!("f{file}.rs")), body).unwrap();
+            }
+            // Skipped files must not disturb the order of the rest
+            fs::write(dir_path.join("blob.rs"), b"\0\x01binary").unwrap();
+            fs::write(dir_path.join("latin1.rs"), b"caf\xe9").unwrap();
+        }
+
+        let config = Config {
+            dir: root.to_path_buf(),
+            patterns: vec!["**/*.rs".to_string()],
+            ..Config::default()
+        };
+        let files = collect_files(&config).unwrap();
+        let render = |parallel, format| {
+            let options = ContextOptions {
+                process: ProcessOptions {
+                    line_numbers: true,
+                    ..ProcessOptions::default()
+                },
+                render: RenderOptions {
+                    format,
+                    ..RenderOptions::default()
+                },
+                parallel,
+                ..ContextOptions::default()
+            };
+            render_context(&files, &options).unwrap()
+        };
+
+        for format in [Format::Markdown, Format::Json] {
+            let serial = render(false, format);
+            assert!(!serial.contains("blob.rs") && !serial.contains("latin1.rs"));
+            for _ in 0..4 {
+                assert_eq!(render(true, format), serial);
+            }
+        }
+    }
+
+    #[test]
+    fn test_gitignored_dir_absent_from_tree_and_content() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        // Gitignore rules only apply inside a git repository
+        fs::create_dir(root.join(".git")).unwrap();
+        fs::write(root.join(".gitignore"), "build/\n").unwrap();
+        fs::create_dir_all(root.join("build")).unwrap();
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("build").join("out.rs"), "fn generated() {}\n").unwrap();
+        fs::write(root.join("src").join("main.rs"), "fn main() {}\n").unwrap();
+
+        let config = Config {
+            dir: root.to_path_buf(),
+            patterns: vec!["**/*.rs".to_string()],
+            ..Config::default()
+        };
+        let filters = config.filters();
+        let files = collect_files_with(&config, &filters).unwrap();
+        assert_eq!(files, vec![root.join("src").join("main.rs")]);
+
+        let tree_files = walk_files(&config, &filters);
+        let tree = render_tree(&TreeContext {
+            root,
+            files: &tree_files,
+            max_depth: None,
+            style: &ASCII_TREE,
+            annotate: false,
+            bpe: None,
+        });
+        assert!(tree.contains("main.rs"), "{tree}");
+        assert!(
+            !tree.contains("build") && !tree.contains("out.rs"),
+            "{tree}"
+        );
+    }
+}
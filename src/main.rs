@@ -1,27 +1,45 @@
 // ./src/main.rs
-use clap::Parser;
-use glob::Pattern;
-use ignore::WalkBuilder;
+use clap::parser::ValueSource;
+use clap::{Command, CommandFactory, Parser};
+use create_context::{
+    apply_token_budget, collect_files_detailed, count_tokens, count_tokens_chunked,
+    file_token_counts, flag_large_files, load_tokenizer, process_file, process_files,
+    relative_path, render_chunks, render_files, render_jsonl_line, render_markdown, render_tree,
+    set_quiet, table_of_contents, truncate_file, truncate_files, try_process_file,
+    try_process_files, warn, Config, Format, GitStatus, ProcessOptions, ProcessedFile,
+    RenderOptions, SortKey, Tokenizer, TreeContext, ASCII_TREE, JSON_SCHEMA, UNICODE_TREE,
+};
+use dialoguer::MultiSelect;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use rayon::prelude::*;
-use std::collections::HashMap;
-use std::fs::{self, DirEntry};
-use std::io::{self, Write};
-use std::path::Path;
-use tiktoken_rs::cl100k_base;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use tiktoken_rs::CoreBPE;
 
-/// CLI arguments
+/// CLI arguments. Defaults for any of these can also be set in a
+/// `create-context.toml` config file.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[arg(long, short, default_value = ".")]
-    dir: String,
+    #[arg(
+        long,
+        short,
+        default_value = ".",
+        help = "Directory to walk (can be used multiple times)"
+    )]
+    dir: Vec<String>,
 
     #[arg(
         long,
         short,
         num_args = 1..,
         value_name = "GLOB",
-        conflicts_with = "files",
         help = "Glob patterns to match (can be used multiple times)"
     )]
     patterns: Vec<String>,
@@ -30,448 +48,1831 @@ struct Args {
         long,
         short,
         num_args = 1..,
-        conflicts_with = "patterns",
-        help = "List of specific files (space-separated)"
+        help = "List of specific files, added to any matched by --patterns"
     )]
     files: Vec<String>,
 
-    #[arg(long, help = "Disable printing of directory tree structure")]
-    no_tree: bool,
+    #[arg(
+        long,
+        value_name = "EXT",
+        value_delimiter = ',',
+        help = "Only include files with these extensions, e.g. 'rs,toml'"
+    )]
+    ext: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "EXT",
+        value_delimiter = ',',
+        help = "Exclude files with these extensions, e.g. 'lock,svg'"
+    )]
+    no_ext: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Exit with an error on invalid glob patterns, missing --files entries, and binary, unreadable or undecodable files"
+    )]
+    strict: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Abort if more than N files match, to catch overly broad patterns"
+    )]
+    max_files: Option<usize>,
+
+    #[arg(
+        long,
+        requires = "max_files",
+        help = "Continue even if --max-files is exceeded"
+    )]
+    force: bool,
+
+    #[arg(long, help = "Disable printing of directory tree structure")]
+    no_tree: bool,
+
+    #[arg(long, help = "Enable parallel processing of file contents")]
+    parallel: bool,
+
+    #[arg(long, help = "Count and print the number of tokens in output")]
+    count_tokens: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["output", "clipboard", "gzip"],
+        help = "Print only the output's token count, as a bare number on stdout"
+    )]
+    count_only: bool,
+
+    #[arg(
+        long,
+        help = "Ignore test files and strip Rust test modules and #[test] functions"
+    )]
+    ignore_tests: bool,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Also treat files under directories named NAME as tests for --ignore-tests (repeatable)"
+    )]
+    test_dir: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "SUFFIX",
+        help = "Also treat file names ending with SUFFIX, e.g. _spec.rs, as tests for --ignore-tests (repeatable)"
+    )]
+    test_suffix: Vec<String>,
+
+    #[arg(
+        long,
+        short,
+        value_name = "PATH",
+        help = "Write output to a file instead of stdout"
+    )]
+    output: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Also write the same files as a JSON document to PATH, without reading them twice"
+    )]
+    also_json: Option<PathBuf>,
+
+    #[arg(long, help = "Print the JSON Schema of --format json output and exit")]
+    print_schema: bool,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = [
+            "output", "clipboard", "gzip", "count_only", "tree_only",
+            "prepend", "prepend_file", "append", "append_file",
+        ],
+        help = "Write each file to its own DIR/<path>.md; the tree still goes to stdout"
+    )]
+    split_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write each included file's SHA-256, size and path to PATH"
+    )]
+    manifest: Option<PathBuf>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["patterns", "files"],
+        help = "Read newline-separated file paths from stdin"
+    )]
+    stdin: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Format::Markdown,
+        help = "Output format"
+    )]
+    format: Format,
+
+    #[arg(long, help = "Print per-file token counts, largest first")]
+    token_breakdown: bool,
+
+    #[arg(long, help = "Print file and token totals per language, largest first")]
+    lang_summary: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Stop including files once the token budget would be exceeded"
+    )]
+    max_tokens: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Warn about any single file over N tokens, naming it"
+    )]
+    max_tokens_per_file: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Cut any file over N tokens down to its first N tokens, marked with '... [truncated]'"
+    )]
+    truncate_tokens: Option<usize>,
+
+    #[arg(
+        long,
+        requires = "max_tokens_per_file",
+        help = "Leave out files over --max-tokens-per-file instead of only warning"
+    )]
+    skip_large_files: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Tokenizer::Cl100k,
+        help = "Tokenizer used for token counting"
+    )]
+    tokenizer: Tokenizer,
+
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = parse_size,
+        help = "Skip files larger than this size (accepts suffixes like 10k, 2M)"
+    )]
+    max_file_size: Option<u64>,
+
+    #[arg(long, help = "Include files that look binary instead of skipping them")]
+    no_skip_binary: bool,
+
+    #[arg(long, help = "Prefix each line of file content with its line number")]
+    line_numbers: bool,
+
+    #[arg(
+        long,
+        short = 'x',
+        value_name = "GLOB",
+        help = "Glob patterns to exclude, taking precedence over includes; prefix with '!' to re-include (can be used multiple times)"
+    )]
+    exclude: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Read more --patterns from a file, one per line (can be used multiple times)"
+    )]
+    patterns_file: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Read more --exclude globs from a file, one per line (can be used multiple times)"
+    )]
+    exclude_file: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Include hidden files and directories (names starting with '.')"
+    )]
+    include_hidden: bool,
+
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Include hidden files or directories named NAME, e.g. .github, without --include-hidden (repeatable)"
+    )]
+    allow_dot: Vec<String>,
+
+    #[arg(
+        long,
+        help = "Include lock files such as Cargo.lock and package-lock.json"
+    )]
+    include_lockfiles: bool,
+
+    #[arg(
+        long,
+        help = "Walk vendored and build directories (node_modules, target, dist, build, .venv, vendor), which are skipped by default"
+    )]
+    no_default_excludes: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Also apply the ignore rules in PATH, in gitignore syntax, e.g. .dockerignore (can be used multiple times)"
+    )]
+    ignore_file: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Don't apply your global gitignore (core.excludesFile, ~/.config/git/ignore)"
+    )]
+    no_global_gitignore: bool,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Limit the directory tree to N levels below the root"
+    )]
+    tree_depth: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Include only the first N lines of each file"
+    )]
+    head: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Replace Rust function bodies with { /* ... */ }, keeping signatures as an API outline"
+    )]
+    skeleton: bool,
+
+    #[arg(
+        long,
+        visible_alias = "base64-binary",
+        help = "Include binary files as base64 with a note on their type and size, instead of skipping them"
+    )]
+    include_binary_as_base64: bool,
+
+    #[arg(long, help = "Convert CRLF and CR line endings to LF")]
+    normalize_eol: bool,
+
+    #[arg(long, help = "Collapse runs of blank lines into a single blank line")]
+    squeeze_blank: bool,
+
+    #[arg(long, help = "Remove spaces and tabs at the end of each line")]
+    trim_trailing_whitespace: bool,
+
+    #[arg(long, help = "Remove comments from file content")]
+    strip_comments: bool,
+
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        help = "Line to write before each file; {path} is replaced by its path"
+    )]
+    separator: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "split_dir",
+        help = "Start with a table of contents linking to each file"
+    )]
+    toc: bool,
+
+    #[arg(
+        long,
+        help = "Start the output with an overview: file count, languages and top-level directories"
+    )]
+    summary_header: bool,
+
+    #[arg(
+        long,
+        short = 'q',
+        help = "Don't print warnings or skipped files on stderr; errors are still reported"
+    )]
+    quiet: bool,
+
+    #[arg(
+        long,
+        short = 'v',
+        conflicts_with = "quiet",
+        help = "Report each file that is left out and why on stderr"
+    )]
+    verbose: bool,
+
+    #[arg(long, help = "Emit file contents without markdown code fences")]
+    raw: bool,
+
+    #[arg(
+        long,
+        help = "Show absolute paths in file headers instead of paths relative to --dir"
+    )]
+    absolute_paths: bool,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with = "absolute_paths",
+        help = "Show file header paths relative to DIR instead of --dir"
+    )]
+    relative_to: Option<PathBuf>,
+
+    #[arg(long, help = "Copy output to the system clipboard instead of stdout")]
+    clipboard: bool,
+
+    #[arg(
+        long,
+        visible_alias = "dry-run",
+        help = "List the files that would be included and exit"
+    )]
+    list: bool,
+
+    #[arg(
+        long,
+        requires = "list",
+        help = "End each path listed by --list with a NUL byte instead of a newline, for xargs -0"
+    )]
+    print0: bool,
+
+    #[arg(
+        long,
+        short = 'i',
+        conflicts_with = "stdin",
+        help = "Pick which of the candidate files to include from a checklist"
+    )]
+    interactive: bool,
+
+    #[arg(long, help = "Print file, byte, line and token totals to stderr")]
+    stats: bool,
+
+    #[arg(
+        long,
+        help = "Show file sizes in the tree, plus token counts when a tokenizer is in use"
+    )]
+    tree_annotate: bool,
+
+    #[arg(
+        long,
+        value_name = "TEXT",
+        help = "Heading above the directory tree instead of 'Directory Structure:'; {root} is replaced by the directory, and an empty TEXT omits it"
+    )]
+    tree_heading: Option<String>,
+
+    #[arg(long, help = "Leave the directory tree out of its ```text fence")]
+    tree_raw: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["no_tree", "gzip", "clipboard"],
+        help = "Print only the directory tree of the selected files, without their contents"
+    )]
+    tree_only: bool,
+
+    #[arg(long, help = "Draw the directory tree with ASCII connectors")]
+    ascii_tree: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = SortKey::Path,
+        help = "Order in which files are listed and emitted"
+    )]
+    sort: SortKey,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Emit the files listed in PATH first, in that order, then the rest in --sort order"
+    )]
+    order_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        short = 'L',
+        help = "Follow symbolic links while walking directories"
+    )]
+    follow_links: bool,
+
+    #[arg(
+        long,
+        help = "Only take files directly in --dir, without descending into subdirectories"
+    )]
+    no_recursive: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "clipboard",
+        help = "Gzip-compress the output; requires --output or a non-terminal stdout"
+    )]
+    gzip: bool,
+
+    #[arg(
+        long,
+        value_name = "TEXT",
+        help = "Text to place at the start of the output, before the tree"
+    )]
+    prepend: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with = "prepend",
+        help = "Read the text for --prepend from a file"
+    )]
+    prepend_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "TEXT",
+        help = "Text to place at the end of the output, after the last file"
+    )]
+    append: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with = "append",
+        help = "Read the text for --append from a file"
+    )]
+    append_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Note each file's git state (modified, staged, untracked) in its header"
+    )]
+    git_status: bool,
+
+    #[arg(
+        long,
+        help = "Note each file's last modification time (ISO-8601, UTC) in its header"
+    )]
+    show_mtime: bool,
+
+    #[arg(
+        long,
+        value_name = "REF",
+        help = "Only include files changed since a git ref, e.g. main or HEAD~3"
+    )]
+    since: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "KEY=LANG",
+        value_delimiter = ',',
+        value_parser = parse_lang_mapping,
+        help = "Language overrides, e.g. '.ino=cpp,.pyi=python,SConstruct=python'"
+    )]
+    lang_map: Vec<(String, String)>,
+}
+
+/// Parses one `--lang-map` entry: `.ext=language` or `filename=language`.
+fn parse_lang_mapping(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, lang)) if !key.trim().is_empty() && !lang.trim().is_empty() => {
+            Ok((key.trim().to_string(), lang.trim().to_string()))
+        }
+        _ => Err(format!(
+            "expected '.ext=language' or 'filename=language', got '{}'",
+            s
+        )),
+    }
+}
+
+/// Parses a byte size such as `512`, `10k` or `2M`. Suffixes are binary
+/// multiples (`k` = 1024).
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (digits, suffix) = s.split_at(split);
+
+    let multiplier: u64 = match suffix.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1 << 10,
+        "m" | "mb" => 1 << 20,
+        "g" | "gb" => 1 << 30,
+        _ => return Err(format!("invalid size suffix '{}'", suffix)),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid size '{}'", s))?;
+
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{}' is too large", s))
+}
+
+/// Joins the whole document in order: the prepended text, the overview and
+/// directory tree, the rendered files and the appended text.
+fn assemble_output(prepend: Option<&str>, tree: &str, files: &str, append: Option<&str>) -> String {
+    let mut output = String::new();
+    if let Some(text) = prepend {
+        output.push_str(text);
+        output.push('\n');
+    }
+    output.push_str(tree);
+    output.push_str(files);
+    if let Some(text) = append {
+        output.push_str(text);
+    }
+    output
+}
+
+/// The markdown tree section: a heading and a fenced tree per root. A custom
+/// `heading` has `{root}` replaced by the root and is left out when empty;
+/// `raw` drops the fence.
+fn tree_section(contexts: &[TreeContext], heading: Option<&str>, raw: bool) -> String {
+    contexts
+        .iter()
+        .map(|ctx| {
+            let heading = match (heading, contexts.len()) {
+                (Some(heading), _) => heading.replace("{root}", &ctx.root.display().to_string()),
+                (None, 1) => "Directory Structure:".to_string(),
+                (None, _) => format!("Directory Structure of {}:", ctx.root.display()),
+            };
+            let mut section = String::new();
+            if !heading.is_empty() {
+                section.push_str(&heading);
+                section.push_str("\n\n");
+            }
+            if raw {
+                section.push_str(&format!("{}\n\n", render_tree(ctx)));
+            } else {
+                section.push_str(&format!("```text\n{}\n```\n\n", render_tree(ctx)));
+            }
+            section
+        })
+        .collect()
+}
+
+/// One tree per root, showing only `root_files` and their directories.
+fn tree_contexts<'a>(
+    configs: &'a [Config],
+    root_files: &'a [Vec<PathBuf>],
+    args: &Args,
+    bpe: Option<&'a CoreBPE>,
+) -> Vec<TreeContext<'a>> {
+    configs
+        .iter()
+        .zip(root_files)
+        .map(|(config, files)| TreeContext {
+            root: &config.dir,
+            files,
+            max_depth: args.tree_depth,
+            style: if args.ascii_tree {
+                &ASCII_TREE
+            } else {
+                &UNICODE_TREE
+            },
+            annotate: args.tree_annotate,
+            bpe,
+        })
+        .collect()
+}
+
+/// The tree as it appears in the document: a markdown section for markdown
+/// output, plain text for JSON and XML, or nothing without `show_tree`.
+fn tree_text(contexts: &[TreeContext], args: &Args, show_tree: bool) -> String {
+    if !show_tree {
+        String::new()
+    } else if args.format.is_markdown() {
+        tree_section(contexts, args.tree_heading.as_deref(), args.tree_raw)
+    } else {
+        plain_trees(contexts)
+    }
+}
+
+/// Narrows each root's files to those in `outputs`, keeping their order.
+fn emitted_root_files(root_files: &[Vec<PathBuf>], outputs: &[ProcessedFile]) -> Vec<Vec<PathBuf>> {
+    let emitted: HashSet<&Path> = outputs.iter().map(|file| file.source.as_path()).collect();
+    root_files
+        .iter()
+        .map(|files| {
+            files
+                .iter()
+                .filter(|path| emitted.contains(path.as_path()))
+                .cloned()
+                .collect()
+        })
+        .collect()
+}
+
+/// The trees as plain text, for JSON output and `--tree-only`. With several
+/// roots, each tree is preceded by its directory.
+fn plain_trees(contexts: &[TreeContext]) -> String {
+    match contexts {
+        [ctx] => render_tree(ctx),
+        _ => contexts
+            .iter()
+            .map(|ctx| format!("{}:\n{}", ctx.root.display(), render_tree(ctx)))
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+    }
+}
+
+/// Prints each file's token count to stderr, largest first, followed by the total.
+fn print_token_breakdown(mut counts: Vec<(&str, usize)>) {
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    let total: usize = counts.iter().map(|(_, n)| n).sum();
+    let width = total.to_string().len();
+
+    eprintln!("Token breakdown:");
+    for (path, n) in &counts {
+        eprintln!("  {:>width$}  {}", n, path);
+    }
+    eprintln!("  {:>width$}  total", total);
+}
+
+/// Aggregate statistics about the included file contents.
+#[derive(Default)]
+struct Stats {
+    files: usize,
+    bytes: usize,
+    lines: usize,
+}
+
+impl Stats {
+    fn add(&mut self, file: &ProcessedFile) {
+        self.files += 1;
+        self.bytes += file.content.len();
+        self.lines += file.content.lines().count();
+    }
+}
+
+/// Prints aggregate statistics about the included file contents to stderr.
+fn print_stats(stats: &Stats, tokens: Option<usize>) {
+    eprintln!("Files: {}", stats.files);
+    eprintln!("Bytes: {}", stats.bytes);
+    eprintln!("Lines: {}", stats.lines);
+    if let Some(tokens) = tokens {
+        eprintln!("Tokens: {}", tokens);
+    }
+}
+
+/// Totals the number of files and tokens for each language, most tokens
+/// first. Takes each file's language and token count.
+fn language_totals<'a>(
+    files: impl IntoIterator<Item = (&'a str, usize)>,
+) -> Vec<(&'a str, (usize, usize))> {
+    let mut totals: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    for (language, tokens) in files {
+        let entry = totals.entry(language).or_default();
+        entry.0 += 1;
+        entry.1 += tokens;
+    }
+    // Stable, so languages with equal token counts stay in name order
+    let mut rows: Vec<_> = totals.into_iter().collect();
+    rows.sort_by_key(|&(_, (_, tokens))| Reverse(tokens));
+    rows
+}
+
+/// `n` followed by `noun`, adding an `s` unless `n` is 1.
+fn plural(n: usize, noun: &str) -> String {
+    if n == 1 {
+        format!("{n} {noun}")
+    } else {
+        format!("{n} {noun}s")
+    }
+}
+
+/// The `--summary-header` overview: totals for the included files, their
+/// languages and the top-level directories they come from. Token counts are
+/// shown when `token_counts` gives one per file.
+fn summary_section(
+    files: &[ProcessedFile],
+    token_counts: Option<&[usize]>,
+    listed: &[PathBuf],
+) -> String {
+    let mut stats = Stats::default();
+    files.iter().for_each(|file| stats.add(file));
+    let counts = files
+        .iter()
+        .enumerate()
+        .map(|(i, file)| (file.language.as_str(), token_counts.map_or(0, |c| c[i])));
+    let languages: Vec<String> = language_totals(counts)
+        .into_iter()
+        .map(|(language, (files, tokens))| {
+            let language = if language.is_empty() {
+                "unknown"
+            } else {
+                language
+            };
+            match token_counts {
+                Some(_) => format!(
+                    "{language} ({}, {})",
+                    plural(files, "file"),
+                    plural(tokens, "token")
+                ),
+                None => format!("{language} ({})", plural(files, "file")),
+            }
+        })
+        .collect();
+    let top_dirs: BTreeSet<String> = listed
+        .iter()
+        .filter_map(|path| {
+            let mut parts = path
+                .components()
+                .filter(|c| matches!(c, Component::Normal(_)));
+            let first = parts.next()?;
+            parts
+                .next()
+                .map(|_| first.as_os_str().to_string_lossy().into_owned())
+        })
+        .collect();
+
+    let mut section = format!(
+        "## Overview\n\n- {}, {}, {}",
+        plural(stats.files, "file"),
+        plural(stats.lines, "line"),
+        plural(stats.bytes, "byte")
+    );
+    if let Some(counts) = token_counts {
+        section.push_str(&format!(", {}", plural(counts.iter().sum(), "token")));
+    }
+    section.push_str(&format!("\n- Languages: {}\n", languages.join(", ")));
+    if !top_dirs.is_empty() {
+        let top_dirs: Vec<String> = top_dirs.into_iter().collect();
+        section.push_str(&format!(
+            "- Top-level directories: {}\n",
+            top_dirs.join(", ")
+        ));
+    }
+    section.push('\n');
+    section
+}
+
+/// Prints the number of files and tokens for each language to stderr, most
+/// tokens first. Takes each file's language and token count.
+fn print_lang_summary<'a>(files: impl IntoIterator<Item = (&'a str, usize)>) {
+    let rows = language_totals(files);
+    let file_width = rows
+        .iter()
+        .map(|(_, (n, _))| n)
+        .max()
+        .unwrap_or(&0)
+        .to_string()
+        .len();
+    let token_width = rows
+        .iter()
+        .map(|(_, (_, n))| n)
+        .max()
+        .unwrap_or(&0)
+        .to_string()
+        .len();
+
+    eprintln!("Languages:");
+    for (language, (files, tokens)) in rows {
+        let language = if language.is_empty() {
+            "(unknown)"
+        } else {
+            language
+        };
+        eprintln!(
+            "  {:>file_width$} files  {:>token_width$} tokens  {}",
+            files, tokens, language
+        );
+    }
+}
+
+/// The collected files as shown to the user: relative to their root, or
+/// joined onto it when there are several roots so the paths are unambiguous.
+fn listed_paths(configs: &[Config], root_files: &[Vec<PathBuf>]) -> Vec<PathBuf> {
+    let mut listed = Vec::new();
+    for (config, files) in configs.iter().zip(root_files) {
+        let canonical_base = config.canonical_dir();
+        for path in files {
+            let relative = relative_path(path, &config.dir, &canonical_base);
+            if configs.len() > 1 {
+                listed.push(config.dir.join(relative));
+            } else {
+                listed.push(relative.to_path_buf());
+            }
+        }
+    }
+    listed
+}
+
+/// `listed_paths` in the order of `matched_files`, which may have been
+/// rearranged by `--order-file`.
+fn listed_in_order(
+    configs: &[Config],
+    root_files: &[Vec<PathBuf>],
+    matched_files: &[PathBuf],
+) -> Vec<PathBuf> {
+    let mut listed: HashMap<&PathBuf, PathBuf> = root_files
+        .iter()
+        .flatten()
+        .zip(listed_paths(configs, root_files))
+        .collect();
+    matched_files
+        .iter()
+        .filter_map(|path| listed.remove(path))
+        .collect()
+}
 
-    #[arg(long, help = "Enable parallel processing of file contents")]
-    parallel: bool,
+/// Shows the collected files as a checklist on the terminal and keeps only
+/// the ones the user selects. Cancelling the picker is an error.
+fn pick_files(configs: &[Config], root_files: &mut [Vec<PathBuf>]) -> io::Result<()> {
+    let items: Vec<String> = listed_paths(configs, root_files)
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    if items.is_empty() {
+        return Ok(());
+    }
+    let selected: HashSet<usize> = MultiSelect::new()
+        .with_prompt("Select files to include (space to toggle, enter to confirm)")
+        .items(&items)
+        .interact_opt()
+        .map_err(io::Error::other)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Interrupted, "No files selected"))?
+        .into_iter()
+        .collect();
 
-    #[arg(long, help = "Count and print the number of tokens in output")]
-    count_tokens: bool,
+    let mut index = 0;
+    for files in root_files.iter_mut() {
+        files.retain(|_| {
+            index += 1;
+            selected.contains(&(index - 1))
+        });
+    }
+    Ok(())
+}
 
-    #[arg(long, help = "Ignore Rust test files and strip test modules")]
-    ignore_tests: bool,
+/// Parses a patterns file: one glob per line, skipping blank lines and
+/// lines starting with `#`.
+fn parse_pattern_lines(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
 }
 
-fn determine_language(file_path: &str) -> String {
-    let filename_to_language: HashMap<&str, &str> = HashMap::from([
-        ("Makefile", "make"),
-        ("CMakeLists.txt", "cmake"),
-        ("Dockerfile", "docker"),
-        (".gitignore", "git"),
-        ("build.gradle", "gradle"),
-        ("Cargo.toml", "rust"),
-        ("package.json", "node"),
-    ]);
-
-    let extension_to_language: HashMap<&str, &str> = HashMap::from([
-        ("rs", "rust"),
-        ("zig", "zig"),
-        ("zon", "zig"),
-        ("go", "go"),
-        ("py", "python"),
-        ("cpp", "cpp"),
-        ("cc", "cpp"),
-        ("cxx", "cpp"),
-        ("hpp", "cpp"),
-        ("hh", "cpp"),
-        ("hxx", "cpp"),
-        ("c", "c"),
-        ("h", "c"),
-        ("cu", "cuda"),
-        ("cuh", "cuda"),
-        ("js", "javascript"),
-        ("ts", "typescript"),
-        ("toml", "toml"),
-        ("yaml", "yaml"),
-        ("yml", "yaml"),
-        ("json", "json"),
-        ("txt", "txt"),
-        ("sh", "bash"),
-        ("md", "markdown"),
-        ("proto", "protobuf"),
-        ("cmake", "cmake"),
-        ("html", "html"),
-        ("css", "css"),
-    ]);
-
-    let path = Path::new(file_path);
-
-    if let Some(file_name) = path.file_name().and_then(|f| f.to_str()) {
-        if let Some(lang) = filename_to_language.get(file_name) {
-            return lang.to_string();
-        }
-    }
-
-    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-        if let Some(lang) = extension_to_language.get(ext) {
-            return lang.to_string();
-        }
-    }
-
-    String::new()
-}
-
-fn comment_syntax(language: &str) -> (&'static str, Option<&'static str>) {
-    match language {
-        "rust" | "cpp" | "c" | "go" | "javascript" | "typescript" | "java" | "swift" | "kotlin" => {
-            ("//", None)
-        }
-        "python" | "bash" | "sh" | "yaml" | "yml" | "toml" | "make" => ("#", None),
-        "lua" => ("--", None),
-        "html" | "xml" => ("<!--", Some("-->")),
-        "css" | "scss" => ("/*", Some("*/")),
-        "json" | "protobuf" => ("//", None),
-        "markdown" => ("<!--", Some("-->")),
-        _ => ("//", None),
-    }
-}
-
-fn is_lock_file(path: &Path) -> bool {
-    if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-        return name.ends_with(".lock")
-            || name == "Cargo.lock"
-            || name == "package-lock.json"
-            || name == "yarn.lock"
-            || name == "Pipfile.lock"
-            || name == "poetry.lock";
-    }
-    false
-}
-
-fn is_excluded(path: &Path, base_dir: &Path) -> bool {
-    if is_lock_file(path) {
-        return true;
-    }
-
-    for component in path.components() {
-        if let std::path::Component::Normal(part) = component {
-            if let Some(part_str) = part.to_str() {
-                if part_str.starts_with('.') {
-                    return true;
-                }
-            }
+fn read_pattern_file(path: &Path) -> io::Result<Vec<String>> {
+    let text = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to read patterns from '{}': {}", path.display(), e),
+        )
+    })?;
+    Ok(parse_pattern_lines(&text))
+}
+
+/// Moves the files listed in `order` to the front of `files`, in the listed
+/// order, leaving the rest in their current order after them. Paths are
+/// compared after canonicalizing. Returns the listed paths that matched no
+/// file.
+fn apply_order<'a>(files: &mut Vec<PathBuf>, order: &'a [PathBuf]) -> Vec<&'a PathBuf> {
+    let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let mut positions: HashMap<PathBuf, usize> = files
+        .iter()
+        .enumerate()
+        .map(|(i, path)| (canonical(path), i))
+        .collect();
+    let mut unmatched = Vec::new();
+    let mut first = Vec::new();
+    for listed in order {
+        match positions.remove(&canonical(listed)) {
+            Some(i) => first.push(i),
+            None => unmatched.push(listed),
         }
     }
+    let mut taken = vec![false; files.len()];
+    first.iter().for_each(|&i| taken[i] = true);
+    let rest = (0..files.len()).filter(|&i| !taken[i]);
+    *files = first
+        .into_iter()
+        .chain(rest)
+        .map(|i| files[i].clone())
+        .collect();
+    unmatched
+}
 
-    is_ignored_by_gitignore(base_dir, path)
+/// Reads an `--order-file`: one path per line, relative to `dir`, skipping
+/// blank lines and lines starting with `#`.
+fn read_order_file(path: &Path, dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let text = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to read order from '{}': {}", path.display(), e),
+        )
+    })?;
+    Ok(parse_pattern_lines(&text)
+        .into_iter()
+        .map(|line| dir.join(line))
+        .collect())
 }
 
-fn is_ignored_by_gitignore(base_dir: &Path, file_path: &Path) -> bool {
-    let parent = file_path.parent().unwrap_or(base_dir);
-    for entry in WalkBuilder::new(parent)
-        .standard_filters(true)
-        .follow_links(true)
-        .build()
-        .flatten()
-    {
-        if entry.path() == file_path {
-            return false;
-        }
-    }
-    true
-}
-
-fn tree_entry_sort(a: &DirEntry, b: &DirEntry) -> std::cmp::Ordering {
-    let a_is_dir = a.path().is_dir();
-    let b_is_dir = b.path().is_dir();
-    match (a_is_dir, b_is_dir) {
-        (true, false) => std::cmp::Ordering::Less,
-        (false, true) => std::cmp::Ordering::Greater,
-        _ => a.file_name().cmp(&b.file_name()),
-    }
-}
-
-fn walk_tree(
-    dir: &Path,
-    prefix: String,
-    is_last: bool,
-    dir_count: &mut usize,
-    file_count: &mut usize,
-    output: &mut Vec<String>,
-    root: &Path,
-) -> io::Result<()> {
-    let connector = if is_last { "└── " } else { "├── " };
-    if prefix.is_empty() {
-        output.push(".".to_string());
-    } else if let Some(name) = dir.file_name() {
-        output.push(format!("{prefix}{connector}{}", name.to_string_lossy()));
-    }
-
-    let mut entries = fs::read_dir(dir)?
-        .filter_map(Result::ok)
-        .filter(|e| !is_excluded(&e.path(), root))
-        .collect::<Vec<_>>();
-
-    entries.sort_by(tree_entry_sort);
-
-    let len = entries.len();
-    for (i, entry) in entries.into_iter().enumerate() {
-        let path = entry.path();
-        let is_last_entry = i == len - 1;
-        let new_prefix = format!("{prefix}{}", if is_last { "    " } else { "│   " });
-
-        if path.is_dir() {
-            *dir_count += 1;
-            walk_tree(
-                &path,
-                new_prefix,
-                is_last_entry,
-                dir_count,
-                file_count,
-                output,
-                root,
-            )?;
-        } else {
-            *file_count += 1;
-            let conn = if is_last_entry {
-                "└── "
-            } else {
-                "├── "
-            };
-            output.push(format!(
-                "{new_prefix}{conn}{}",
-                entry.file_name().to_string_lossy()
-            ));
+/// Reads newline-separated paths from stdin, skipping blank lines and
+/// trimming trailing whitespace.
+fn read_paths_from_stdin() -> io::Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        let path = line.trim_end();
+        if !path.is_empty() {
+            paths.push(path.to_string());
         }
     }
+    Ok(paths)
+}
 
-    Ok(())
+fn output_error(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(
+        e.kind(),
+        format!("Failed to write output to '{}': {}", path.display(), e),
+    )
+}
+
+/// Opens `path` for writing, creating parent directories as needed. An
+/// existing file at `path` is overwritten.
+fn create_output_file(path: &Path) -> io::Result<fs::File> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    fs::File::create(path).map_err(|e| output_error(path, e))
 }
 
-fn print_tree_structure(root: &Path) -> io::Result<()> {
-    let mut dir_count = 1;
-    let mut file_count = 0;
-    let mut lines = Vec::new();
-    walk_tree(
-        root,
-        "".to_string(),
-        true,
-        &mut dir_count,
-        &mut file_count,
-        &mut lines,
-        root,
-    )?;
-
-    println!("Directory Structure:\n");
-    println!("```text");
-    for line in lines {
-        println!("{line}");
-    }
-    println!("\n{} directories, {} files", dir_count, file_count);
-    println!("```");
+/// Writes the rendered context to `path`, creating parent directories as needed.
+/// An existing file at `path` is overwritten.
+fn write_output_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    create_output_file(path)?
+        .write_all(contents)
+        .map_err(|e| output_error(path, e))
+}
 
-    Ok(())
+/// Mirrors a file's displayed path under `dir` with an `.md` suffix. Root,
+/// `.` and `..` components are dropped so every file lands inside `dir`.
+fn split_path(dir: &Path, display_path: &str) -> PathBuf {
+    let mut path: PathBuf = dir.to_path_buf();
+    path.extend(
+        Path::new(display_path)
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(part) => Some(part),
+                _ => None,
+            }),
+    );
+    path.as_mut_os_string().push(".md");
+    path
 }
 
-/// Determines if a given path corresponds to a Rust test file.
-/// This checks for:
-/// - Any `.rs` file inside a directory named `tests`
-/// - Filenames ending with `_test.rs`
-/// - Filenames equal to `tests.rs`
-fn is_rust_test_file(path: &Path) -> bool {
-    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-        if ext == "rs" {
-            if let Some(file_name) = path.file_name().and_then(|f| f.to_str()) {
-                if file_name.ends_with("_test.rs") || file_name == "tests.rs" {
-                    return true;
-                }
-            }
-            for component in path.components() {
-                if let std::path::Component::Normal(part) = component {
-                    if part == "tests" {
-                        return true;
-                    }
-                }
-            }
+/// Reports the output's token count: bare on stdout for `--count-only`,
+/// labelled on stderr otherwise.
+fn print_token_count(count: usize, args: &Args) {
+    if args.count_only {
+        println!("{}", count);
+    } else {
+        eprintln!("Token count: {}", count);
+    }
+}
+
+/// Running totals for `--format jsonl`, which never holds the whole output.
+#[derive(Default)]
+struct StreamTotals {
+    stats: Stats,
+    output_tokens: usize,
+    /// Each written file's path, language and token count.
+    breakdown: Vec<(String, String, usize)>,
+    /// Each written file's path and manifest line, for `--manifest`.
+    manifest: Vec<(String, String)>,
+}
+
+/// Formats a file's manifest line like `sha256sum`, with the size added:
+/// `<sha256>  <bytes>  <path>`.
+fn manifest_line(file: &ProcessedFile) -> (String, String) {
+    let line = format!(
+        "{}  {}  {}\n",
+        file.sha256.as_deref().unwrap_or("-"),
+        file.size,
+        file.path
+    );
+    (file.path.clone(), line)
+}
+
+/// Writes manifest lines sorted by path, so two runs can be diffed.
+fn write_manifest(path: &Path, mut lines: Vec<(String, String)>) -> io::Result<()> {
+    lines.sort();
+    let text: String = lines.into_iter().map(|(_, line)| line).collect();
+    write_output_file(path, text.as_bytes())
+}
+
+/// `process_file`, or under `--strict` `try_process_file`, which fails on
+/// binary, unreadable and undecodable files instead of skipping them.
+fn process_for(
+    path: &Path,
+    options: &ProcessOptions,
+    strict: bool,
+) -> io::Result<Option<ProcessedFile>> {
+    if strict {
+        try_process_file(path, options)
+    } else {
+        Ok(process_file(path, options))
+    }
+}
+
+/// Reports a streamed file over `--max-tokens-per-file`, returning whether it
+/// should be left out.
+fn is_large_file(file: &ProcessedFile, tokens: usize, args: &Args) -> bool {
+    match args.max_tokens_per_file.filter(|&limit| tokens > limit) {
+        Some(limit) if args.skip_large_files => {
+            warn!(
+                "Skipped '{}': {} tokens exceeds the per-file limit of {}",
+                file.path, tokens, limit
+            );
+            true
         }
+        Some(limit) => {
+            warn!(
+                "Warning: '{}' has {} tokens, over the per-file limit of {}",
+                file.path, tokens, limit
+            );
+            false
+        }
+        None => false,
     }
-    false
-}
-
-/// Strips out any `#[cfg(test)] mod tests { ... }` blocks from the given Rust source.
-fn strip_rust_tests(s: &str) -> String {
-    let mut result = String::new();
-    let mut i = 0;
-    let len = s.len();
-    while i < len {
-        if s[i..].starts_with("#[cfg(test)]") {
-            // Look for the following `mod tests`
-            if let Some(mod_pos) = s[i..].find("mod tests") {
-                // Find the `{` after `mod tests`
-                if let Some(brace_offset) = s[i + mod_pos..].find('{') {
-                    let start_brace = i + mod_pos + brace_offset;
-                    // Now find the matching closing brace
-                    let mut depth = 1;
-                    let mut j = start_brace + 1;
-                    while j < len {
-                        let ch = s[j..].chars().next().unwrap();
-                        match ch {
-                            '{' => depth += 1,
-                            '}' => {
-                                depth -= 1;
-                                if depth == 0 {
-                                    j += ch.len_utf8();
-                                    break;
-                                }
-                            }
-                            _ => {}
-                        }
-                        j += ch.len_utf8();
+}
+
+/// Processes files and writes each one as a JSON line as soon as it is ready,
+/// flushing as it goes. In parallel mode lines are written in completion
+/// order; `--max-tokens` needs the sorted order, so it always runs serially.
+fn stream_jsonl(
+    files: &[PathBuf],
+    args: &Args,
+    options: &ProcessOptions,
+    bpe: &CoreBPE,
+    out: &mut (dyn Write + Send),
+) -> io::Result<StreamTotals> {
+    let count_output = args.count_tokens || args.stats;
+    let state = Mutex::new((out, StreamTotals::default()));
+    let emit = |file: ProcessedFile, tokens: usize| -> io::Result<()> {
+        let line = render_jsonl_line(&file, tokens)?;
+        let mut guard = state.lock().unwrap();
+        let (out, totals) = &mut *guard;
+        out.write_all(line.as_bytes())?;
+        out.flush()?;
+        if count_output {
+            totals.output_tokens += count_tokens(bpe, &line);
+        }
+        totals.stats.add(&file);
+        if args.manifest.is_some() {
+            totals.manifest.push(manifest_line(&file));
+        }
+        totals.breakdown.push((file.path, file.language, tokens));
+        Ok(())
+    };
+
+    if args.parallel && args.max_tokens.is_none() {
+        files
+            .par_iter()
+            .try_for_each(|path| match process_for(path, options, args.strict)? {
+                Some(mut file) => {
+                    if let Some(limit) = args.truncate_tokens {
+                        truncate_file(&mut file, limit, bpe);
                     }
-                    i = j;
-                    continue;
-                } else {
-                    // No opening brace found; skip the marker length and continue
-                    i += "#[cfg(test)]".len();
-                    continue;
+                    let tokens = count_tokens(bpe, &file.content);
+                    if is_large_file(&file, tokens, args) {
+                        return Ok(());
+                    }
+                    emit(file, tokens)
                 }
-            } else {
-                // No `mod tests` after `#[cfg(test)]`; skip the marker and continue
-                i += "#[cfg(test)]".len();
+                None => Ok(()),
+            })?;
+    } else {
+        let mut used = 0;
+        for (i, path) in files.iter().enumerate() {
+            let Some(mut file) = process_for(path, options, args.strict)? else {
                 continue;
+            };
+            if let Some(limit) = args.truncate_tokens {
+                truncate_file(&mut file, limit, bpe);
             }
-        } else {
-            let ch = s[i..].chars().next().unwrap();
-            result.push(ch);
-            i += ch.len_utf8();
+            let tokens = count_tokens(bpe, &file.content);
+            if is_large_file(&file, tokens, args) {
+                continue;
+            }
+            if let Some(max_tokens) = args.max_tokens.filter(|&max| used + tokens > max) {
+                for path in &files[i..] {
+                    warn!(
+                        "Dropped '{}': token budget of {} exceeded",
+                        path.display(),
+                        max_tokens
+                    );
+                }
+                break;
+            }
+            used += tokens;
+            emit(file, tokens)?;
         }
     }
-    // Append any remainder
-    if i < len {
-        result.push_str(&s[i..]);
+
+    Ok(state.into_inner().unwrap().1)
+}
+
+/// Resolves a `--prepend`/`--append` style option given either inline or as
+/// a file. The text is normalized to end with a single newline.
+fn wrapper_text(text: Option<&str>, file: Option<&Path>) -> io::Result<Option<String>> {
+    let text = match (text, file) {
+        (Some(text), _) => text.to_string(),
+        (None, Some(path)) => fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read '{}': {}", path.display(), e),
+            )
+        })?,
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(format!("{}\n", text.trim_end())))
+}
+
+fn gzip(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(bytes)?;
+    encoder.finish()
+}
+
+/// Places `text` on the system clipboard.
+fn copy_to_clipboard(text: String) -> io::Result<()> {
+    let mut clipboard = arboard::Clipboard::new()
+        .map_err(|e| io::Error::other(format!("Clipboard is not available: {}", e)))?;
+    clipboard
+        .set_text(text)
+        .map_err(|e| io::Error::other(format!("Failed to copy to clipboard: {}", e)))
+}
+
+/// Config file holding default arguments, looked up in the current directory
+/// and in `--dir`.
+const CONFIG_FILENAME: &str = "create-context.toml";
+
+fn invalid_config(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Loads the config file from `dir`, if there is one.
+fn load_config(dir: &Path) -> io::Result<Option<toml::Table>> {
+    let path = dir.join(CONFIG_FILENAME);
+    if !path.is_file() {
+        return Ok(None);
     }
-    result
+    let text = fs::read_to_string(&path)?;
+    text.parse::<toml::Table>()
+        .map(Some)
+        .map_err(|e| invalid_config(format!("Invalid config '{}': {}", path.display(), e)))
 }
 
-fn process_file(file_path: &Path, ignore_tests: bool) -> Option<(String, String)> {
-    let mut content = fs::read_to_string(file_path).ok()?;
-    let language = determine_language(&file_path.to_string_lossy());
+fn config_scalar(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(s) => Some(s.clone()),
+        toml::Value::Integer(i) => Some(i.to_string()),
+        toml::Value::Float(f) => Some(f.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
 
-    // If ignoring tests and this is a Rust file, strip out test modules
-    if ignore_tests && language == "rust" {
-        content = strip_rust_tests(&content);
+/// Flattens a config value into the values passed to a value-taking argument.
+fn config_values(value: &toml::Value) -> Option<Vec<String>> {
+    match value {
+        toml::Value::Array(items) => items.iter().map(config_scalar).collect(),
+        _ => config_scalar(value).map(|v| vec![v]),
     }
+}
 
-    let (start, end) = comment_syntax(&language);
-    let mut buf = String::new();
-    use std::fmt::Write;
+/// Turns config entries into command-line arguments. Keys already given on
+/// the command line, or conflicting with something that was, are skipped.
+fn config_to_args(
+    config: &toml::Table,
+    command: &Command,
+    cli_ids: &[String],
+) -> io::Result<Vec<OsString>> {
+    let mut argv = Vec::new();
+    for (key, value) in config {
+        let id = key.replace('-', "_");
+        let arg = command
+            .get_arguments()
+            .find(|a| a.get_id() == id.as_str())
+            .ok_or_else(|| {
+                invalid_config(format!("Unknown key '{}' in {}", key, CONFIG_FILENAME))
+            })?;
 
-    writeln!(buf, "```{}", language).ok()?;
-    if let Some(end) = end {
-        writeln!(buf, "{} {} {}", start, file_path.display(), end).ok()?;
-    } else {
-        writeln!(buf, "{} {}", start, file_path.display()).ok()?;
+        let overridden = cli_ids.iter().any(|cli_id| {
+            cli_id == &id
+                || command
+                    .get_arg_conflicts_with(arg)
+                    .iter()
+                    .any(|c| c.get_id() == cli_id.as_str())
+                || command
+                    .get_arguments()
+                    .filter(|a| a.get_id() == cli_id.as_str())
+                    .flat_map(|a| command.get_arg_conflicts_with(a))
+                    .any(|c| c.get_id() == id.as_str())
+        });
+        if overridden {
+            continue;
+        }
+
+        let flag = format!("--{}", arg.get_long().unwrap_or(id.as_str()));
+        if arg.get_action().takes_values() {
+            let values = config_values(value).ok_or_else(|| {
+                invalid_config(format!(
+                    "Unsupported value for '{}' in {}",
+                    key, CONFIG_FILENAME
+                ))
+            })?;
+            argv.extend(values.into_iter().map(|v| format!("{flag}={v}").into()));
+        } else {
+            match value.as_bool() {
+                Some(true) => argv.push(flag.into()),
+                Some(false) => {}
+                None => {
+                    return Err(invalid_config(format!(
+                        "Expected true or false for '{}' in {}",
+                        key, CONFIG_FILENAME
+                    )))
+                }
+            }
+        }
     }
-    write!(buf, "{}", content).ok()?;
-    writeln!(buf, "```").ok()?;
-    writeln!(buf).ok()?;
+    Ok(argv)
+}
 
-    Some((file_path.to_string_lossy().to_string(), buf))
+/// Parses the command line, filling in defaults from config files. Precedence,
+/// highest first: the command line, the config in `--dir`, the config in the
+/// current directory.
+fn parse_args() -> io::Result<Args> {
+    parse_args_from(std::env::args_os().collect(), Path::new("."))
 }
 
-/// Count tokens using the cl100k_base tokenizer (OpenAI GPT-4 / GPT-3.5)
-fn count_tokens(text: &str) -> usize {
-    let bpe = cl100k_base().expect("Failed to load tokenizer");
-    bpe.encode_with_special_tokens(text).len()
+/// `parse_args` for the command line `cli`, run from `cwd`.
+fn parse_args_from(cli: Vec<OsString>, cwd: &Path) -> io::Result<Args> {
+    let command = Args::command();
+    let matches = command.clone().get_matches_from(&cli);
+    let cli_ids: Vec<String> = command
+        .get_arguments()
+        .map(|arg| arg.get_id().as_str().to_string())
+        .filter(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+        .collect();
+
+    let mut config = load_config(cwd)?.unwrap_or_default();
+    let dir = match matches.value_source("dir") {
+        Some(ValueSource::CommandLine) => matches.get_one::<String>("dir").cloned(),
+        _ => config
+            .get("dir")
+            .and_then(config_values)
+            .and_then(|dirs| dirs.into_iter().next()),
+    }
+    .unwrap_or_else(|| ".".to_string());
+    let dir = cwd.join(dir);
+
+    let same_dir = match (dir.canonicalize(), cwd.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    };
+    if !same_dir {
+        if let Some(dir_config) = load_config(&dir)? {
+            config.extend(dir_config);
+        }
+    }
+
+    let mut argv = vec![cli.first().cloned().unwrap_or_default()];
+    argv.extend(config_to_args(&config, &command, &cli_ids)?);
+    argv.extend(cli.into_iter().skip(1));
+    Ok(Args::parse_from(argv))
 }
 
-fn main() -> io::Result<()> {
-    let args = Args::parse();
-    let mut matched_files = Vec::new();
-    let ignore = args.ignore_tests;
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    if !args.files.is_empty() {
-        for file in &args.files {
-            let full_path = Path::new(&args.dir).join(file).canonicalize()?;
+fn run() -> io::Result<()> {
+    let mut args = parse_args()?;
+    if args.print_schema {
+        io::stdout().write_all(JSON_SCHEMA.as_bytes())?;
+        return Ok(());
+    }
+    // --count-only is --count-tokens with nothing else on stdout
+    args.count_tokens |= args.count_only;
+    set_quiet(args.quiet);
+    for path in &args.patterns_file {
+        args.patterns.extend(read_pattern_file(path)?);
+    }
+    for path in &args.exclude_file {
+        args.exclude.extend(read_pattern_file(path)?);
+    }
+    // Checked up front so a typo fails instead of quietly ignoring nothing
+    if let Some(path) = args.ignore_file.iter().find(|path| !path.is_file()) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Ignore file '{}' not found", path.display()),
+        ));
+    }
+    let files = if args.stdin {
+        read_paths_from_stdin()?
+    } else {
+        args.files.clone()
+    };
+    if args.dir.len() > 1 && !files.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--files and --stdin can only be used with a single --dir",
+        ));
+    }
+    if args.interactive && !io::stderr().is_terminal() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--interactive needs a terminal",
+        ));
+    }
+    // With nothing selected, the picker offers every file in the walk
+    let patterns = if args.interactive
+        && args.patterns.is_empty()
+        && files.is_empty()
+        && args.ext.is_empty()
+    {
+        vec!["**/*".to_string()]
+    } else {
+        args.patterns.clone()
+    };
+    let configs: Vec<Config> = args
+        .dir
+        .iter()
+        .map(|dir| Config {
+            dir: PathBuf::from(dir),
+            patterns: patterns.clone(),
+            files: files.clone(),
+            exclude: args.exclude.clone(),
+            ignore_tests: args.ignore_tests,
+            test_dirs: args.test_dir.clone(),
+            test_suffixes: args.test_suffix.clone(),
+            include_hidden: args.include_hidden,
+            allow_dot: args.allow_dot.clone(),
+            include_lockfiles: args.include_lockfiles,
+            follow_links: args.follow_links,
+            max_depth: args.no_recursive.then_some(1),
+            no_default_excludes: args.no_default_excludes,
+            ignore_files: args.ignore_file.clone(),
+            no_global_gitignore: args.no_global_gitignore,
+            absolute_paths: args.absolute_paths,
+            sort: args.sort,
+            since: args.since.clone(),
+            extensions: args.ext.clone(),
+            exclude_extensions: args.no_ext.clone(),
+            strict: args.strict,
+            verbose: args.verbose,
+        })
+        .collect();
 
-            if !full_path.exists() || !full_path.is_file() {
-                eprintln!("Warning: '{}' is not a valid file.", full_path.display());
-                continue;
-            }
+    // Each root is collected on its own, in order; a file reachable from
+    // several roots is kept under the first.
+    let mut seen = HashSet::new();
+    let mut root_files = Vec::new();
+    let mut invalid = 0;
+    for config in &configs {
+        let collected = collect_files_detailed(config, &config.filters())?;
+        let mut files = collected.files;
+        files.retain(|path| seen.insert(path.canonicalize().unwrap_or_else(|_| path.clone())));
+        root_files.push(files);
+        invalid += collected.invalid.len();
+    }
+    if invalid > 0 {
+        let summary = format!(
+            "{} of {} specified files were skipped",
+            invalid,
+            files.len()
+        );
+        if args.strict {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, summary));
+        }
+        warn!("{summary}");
+    }
+    // --list reads nothing, and is how to see what a broad pattern matched
+    if let Some(max_files) = args.max_files.filter(|_| !args.force && !args.list) {
+        let count: usize = root_files.iter().map(Vec::len).sum();
+        if count > max_files {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} files matched, more than --max-files {}; narrow --dir or --patterns, \
+                     or pass --force to continue anyway",
+                    count, max_files
+                ),
+            ));
+        }
+    }
+    if args.interactive {
+        pick_files(&configs, &mut root_files)?;
+    }
+    let mut matched_files: Vec<PathBuf> = root_files.concat();
+    if let Some(path) = &args.order_file {
+        let order = read_order_file(path, &configs[0].dir)?;
+        for listed in apply_order(&mut matched_files, &order) {
+            warn!(
+                "Warning: '{}' in --order-file is not among the matched files",
+                listed.display()
+            );
+        }
+    }
 
-            if is_excluded(&full_path, Path::new(&args.dir)) {
-                continue;
+    if args.list {
+        let mut stdout = io::stdout().lock();
+        for path in listed_in_order(&configs, &root_files, &matched_files) {
+            if args.print0 {
+                // Raw bytes, so names that are not valid UTF-8 survive too
+                stdout.write_all(path.as_os_str().as_encoded_bytes())?;
+                stdout.write_all(b"\0")?;
+            } else {
+                writeln!(stdout, "{}", path.display())?;
             }
+        }
+        return Ok(());
+    }
 
-            if ignore && is_rust_test_file(&full_path) {
-                continue;
-            }
+    let needs_tokenizer = args.count_tokens
+        || args.token_breakdown
+        || args.lang_summary
+        || args.max_tokens.is_some()
+        || args.max_tokens_per_file.is_some()
+        || args.truncate_tokens.is_some()
+        || args.clipboard
+        || args.also_json.is_some()
+        || matches!(args.format, Format::Json | Format::Jsonl);
+    let bpe = needs_tokenizer
+        .then(|| load_tokenizer(args.tokenizer))
+        .transpose()?;
+    let mut git_status = None;
+    if args.git_status {
+        let mut status = GitStatus::default();
+        for config in &configs {
+            status.merge(GitStatus::load(&config.dir)?);
+        }
+        git_status = Some(status);
+    }
 
-            matched_files.push(full_path);
+    let show_tree = !args.no_tree && args.format != Format::Jsonl;
+
+    if args.tree_only {
+        let mut text = plain_trees(&tree_contexts(&configs, &root_files, &args, bpe.as_ref()));
+        text.push('\n');
+        match &args.output {
+            Some(path) => write_output_file(path, text.as_bytes())?,
+            None => io::stdout().write_all(text.as_bytes())?,
         }
-    } else {
-        let patterns: Vec<Pattern> = args
-            .patterns
-            .iter()
-            .filter_map(|p| match Pattern::new(p) {
-                Ok(pat) => Some(pat),
-                Err(e) => {
-                    eprintln!("Invalid glob pattern '{}': {}", p, e);
-                    None
-                }
+        return Ok(());
+    }
+
+    if args.gzip && args.output.is_none() && io::stdout().is_terminal() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Refusing to write gzip data to a terminal; use --output <PATH> or redirect stdout",
+        ));
+    }
+    if args.also_json.is_some() && args.format == Format::Jsonl {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--also-json cannot be used with --format jsonl",
+        ));
+    }
+    if args.gzip && args.format == Format::Jsonl {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--gzip cannot be used with --format jsonl",
+        ));
+    }
+
+    let prepend = wrapper_text(args.prepend.as_deref(), args.prepend_file.as_deref())?;
+    let append = wrapper_text(args.append.as_deref(), args.append_file.as_deref())?;
+    if !args.format.is_markdown() && (prepend.is_some() || append.is_some()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--prepend and --append can only be used with markdown output",
+        ));
+    }
+    if !args.format.is_markdown() && args.separator.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--separator can only be used with markdown output",
+        ));
+    }
+    if !args.format.is_markdown() && args.summary_header {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--summary-header can only be used with markdown output",
+        ));
+    }
+    if !args.format.is_markdown() && args.toc {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--toc can only be used with markdown output",
+        ));
+    }
+    if !args.format.is_markdown() && args.split_dir.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--split-dir can only be used with markdown output",
+        ));
+    }
+
+    let relative_to = args
+        .relative_to
+        .as_ref()
+        .map(|dir| {
+            dir.canonicalize().map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Invalid --relative-to '{}': {}", dir.display(), e),
+                )
             })
-            .collect();
+        })
+        .transpose()?;
+    // Headers are relative to a single --dir unless asked otherwise; with
+    // several, the dir prefix tells their files apart
+    let base_dir = match configs.as_slice() {
+        [config] if !args.absolute_paths => Some(config.canonical_dir()),
+        _ => None,
+    };
+    let options = ProcessOptions {
+        ignore_tests: args.ignore_tests,
+        max_file_size: args.max_file_size,
+        skip_binary: !args.no_skip_binary,
+        line_numbers: args.line_numbers,
+        strip_comments: args.strip_comments,
+        skeleton: args.skeleton,
+        base64_binary: args.include_binary_as_base64,
+        head: args.head,
+        git_status,
+        show_mtime: args.show_mtime,
+        lang_map: args.lang_map.iter().cloned().collect(),
+        normalize_eol: args.normalize_eol,
+        squeeze_blank: args.squeeze_blank,
+        trim_trailing_whitespace: args.trim_trailing_whitespace,
+        relative_to,
+        base_dir,
+        hash_contents: args.manifest.is_some(),
+    };
 
-        for result in WalkBuilder::new(&args.dir)
-            .follow_links(true)
-            .standard_filters(true)
-            .build()
-        {
-            let entry = match result {
-                Ok(e) => e,
-                Err(e) => {
-                    eprintln!("Error reading directory entry: {}", e);
-                    continue;
-                }
-            };
+    if let (Format::Jsonl, Some(bpe)) = (args.format, bpe.as_ref()) {
+        if args.clipboard {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--clipboard cannot be used with --format jsonl",
+            ));
+        }
+        let mut out: Box<dyn Write + Send> = match &args.output {
+            Some(path) => Box::new(io::BufWriter::new(create_output_file(path)?)),
+            None if args.count_tokens => Box::new(io::sink()),
+            None => Box::new(io::stdout()),
+        };
+        let totals = stream_jsonl(&matched_files, &args, &options, bpe, &mut out)?;
+        if let Some(path) = &args.manifest {
+            write_manifest(path, totals.manifest)?;
+        }
+
+        if args.count_tokens {
+            print_token_count(totals.output_tokens, &args);
+        }
+        if args.stats {
+            print_stats(&totals.stats, Some(totals.output_tokens));
+        }
+        if args.token_breakdown {
+            print_token_breakdown(
+                totals
+                    .breakdown
+                    .iter()
+                    .map(|(path, _, n)| (path.as_str(), *n))
+                    .collect(),
+            );
+        }
+        if args.lang_summary {
+            print_lang_summary(
+                totals
+                    .breakdown
+                    .iter()
+                    .map(|(_, language, n)| (language.as_str(), *n)),
+            );
+        }
+        return Ok(());
+    }
 
-            let path = entry.path();
+    // Processing preserves the order of `matched_files`, in parallel or not
+    let mut outputs = if args.strict {
+        try_process_files(&matched_files, &options, args.parallel)?
+    } else {
+        process_files(&matched_files, &options, args.parallel)
+    };
 
-            if entry.file_type().is_some_and(|ft| ft.is_file())
-                && !is_excluded(path, Path::new(&args.dir))
-            {
-                if ignore && is_rust_test_file(path) {
-                    continue;
-                }
+    let render_options = RenderOptions {
+        format: args.format,
+        raw: args.raw,
+        separator: args.separator.clone(),
+        toc: args.toc,
+    };
 
-                let relative_path = path.strip_prefix(&args.dir).unwrap_or(path);
-                let relative_path_str = relative_path.to_string_lossy();
-                if patterns.iter().any(|pat| pat.matches(&relative_path_str)) {
-                    matched_files.push(path.to_path_buf());
-                }
+    if let (Some(limit), Some(bpe)) = (args.truncate_tokens, bpe.as_ref()) {
+        truncate_files(&mut outputs, limit, bpe, args.parallel);
+    }
+
+    if let (Some(limit), Some(bpe)) = (args.max_tokens_per_file, bpe.as_ref()) {
+        flag_large_files(
+            &mut outputs,
+            limit,
+            args.skip_large_files,
+            bpe,
+            args.parallel,
+        );
+    }
+
+    let mut file_counts = bpe
+        .as_ref()
+        .filter(|_| args.token_breakdown || args.lang_summary || args.summary_header)
+        .map(|bpe| file_token_counts(bpe, &outputs, &render_options, args.parallel));
+
+    // Tokens of everything around the files: the tree, contents list,
+    // overview and wrapper text, for the given files
+    let overhead_tokens = |bpe: &CoreBPE, outputs: &[ProcessedFile], counts: Option<&[usize]>| {
+        let root_files = emitted_root_files(&root_files, outputs);
+        let contexts = tree_contexts(&configs, &root_files, &args, Some(bpe));
+        let mut overhead: Vec<String> =
+            [&prepend, &append].into_iter().flatten().cloned().collect();
+        overhead.push(tree_text(&contexts, &args, show_tree));
+        if args.toc && args.format.is_markdown() {
+            overhead.push(table_of_contents(outputs));
+        }
+        if args.summary_header {
+            let listed = listed_paths(&configs, &root_files);
+            overhead.push(summary_section(outputs, counts, &listed));
+        }
+        overhead
+            .iter()
+            .map(|text| count_tokens(bpe, text))
+            .sum::<usize>()
+    };
+
+    if let (Some(max_tokens), Some(bpe)) = (args.max_tokens, bpe.as_ref()) {
+        // Leave room for the text around the files so the whole output fits
+        // the budget. It is measured with every file still in; dropping files
+        // only shortens it.
+        let reserved = overhead_tokens(bpe, &outputs, file_counts.as_deref());
+        apply_token_budget(
+            &mut outputs,
+            max_tokens,
+            reserved,
+            bpe,
+            &render_options,
+            args.parallel,
+        );
+        // The budget keeps a prefix of the files, and so of their counts
+        if let Some(counts) = file_counts.as_mut() {
+            counts.truncate(outputs.len());
+        }
+        let overhead = overhead_tokens(bpe, &outputs, file_counts.as_deref());
+        if overhead > max_tokens {
+            let message = format!(
+                "The tree, contents list, overview and --prepend/--append text alone \
+                 take {} tokens, over the --max-tokens budget of {}",
+                overhead, max_tokens
+            );
+            if args.strict {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
             }
+            warn!("Warning: {message}");
         }
     }
 
-    matched_files.sort();
+    // The tree and overview show exactly the files that are emitted, leaving
+    // out any skipped while processing or dropped by the budget
+    let root_files = emitted_root_files(&root_files, &outputs);
+    let tree_contexts = tree_contexts(&configs, &root_files, &args, bpe.as_ref());
 
-    if !args.no_tree {
-        print_tree_structure(Path::new(&args.dir))?;
-        println!();
+    if let Some(path) = &args.manifest {
+        write_manifest(path, outputs.iter().map(manifest_line).collect())?;
     }
 
-    let outputs: Vec<(String, String)> = if args.parallel {
-        matched_files
-            .par_iter()
-            .filter_map(|file_path| process_file(file_path, ignore))
-            .collect()
+    // Rendered from the same processed files as the main output
+    if let Some(path) = &args.also_json {
+        let json_options = RenderOptions {
+            format: Format::Json,
+            ..RenderOptions::default()
+        };
+        let tree = show_tree.then(|| plain_trees(&tree_contexts));
+        let json = render_files(&outputs, tree, &json_options, bpe.as_ref())?;
+        write_output_file(path, json.as_bytes())?;
+    }
+
+    let mut preamble = String::new();
+    if args.summary_header {
+        let listed = listed_paths(&configs, &root_files);
+        preamble.push_str(&summary_section(&outputs, file_counts.as_deref(), &listed));
+    }
+    // The tree is part of the document, so --output, --clipboard, --gzip and
+    // token counts all see it just like the file contents
+    let tree = if matches!(args.format, Format::Json | Format::Xml) && show_tree {
+        Some(plain_trees(&tree_contexts))
     } else {
-        matched_files
-            .iter()
-            .filter_map(|file_path| process_file(file_path, ignore))
-            .collect()
+        preamble.push_str(&tree_text(&tree_contexts, &args, show_tree));
+        None
     };
+    let chunks = render_chunks(&outputs, tree, &render_options, bpe.as_ref())?;
+    // When only a count is asked for nothing is written, so the pieces are
+    // counted one by one instead of being joined into the whole output
+    let writes_output =
+        (!args.count_tokens || args.output.is_some() || args.clipboard || args.gzip)
+            && args.split_dir.is_none();
+    let final_output = writes_output.then(|| {
+        assemble_output(
+            prepend.as_deref(),
+            &preamble,
+            &chunks.concat(),
+            append.as_deref(),
+        )
+    });
+
+    let token_count = bpe
+        .as_ref()
+        .filter(|_| args.count_tokens || args.stats || args.clipboard)
+        .map(|bpe| match &final_output {
+            Some(output) => count_tokens_chunked(bpe, output),
+            None => {
+                let prepend = prepend.as_ref().map(|text| format!("{text}\n"));
+                prepend
+                    .iter()
+                    .chain([&preamble])
+                    .chain(&chunks)
+                    .chain(append.iter())
+                    .map(|piece| count_tokens_chunked(bpe, piece))
+                    .sum()
+            }
+        });
 
-    let mut outputs = outputs;
-    outputs.sort_by(|a, b| a.0.cmp(&b.0));
+    if let Some(token_count) = token_count.filter(|_| args.count_tokens) {
+        print_token_count(token_count, &args);
+    }
 
-    let mut final_output = Vec::new();
-    for (_, chunk) in outputs {
-        write!(final_output, "{}", chunk)?;
+    if args.stats {
+        let mut stats = Stats::default();
+        outputs.iter().for_each(|file| stats.add(file));
+        print_stats(&stats, token_count);
     }
 
-    if args.count_tokens {
-        let output_str = String::from_utf8_lossy(&final_output);
-        let token_count = count_tokens(&output_str);
-        eprintln!("Token count: {}", token_count);
-    } else {
-        io::stdout().write_all(&final_output)?;
+    if let Some(counts) = file_counts.as_ref().filter(|_| args.token_breakdown) {
+        print_token_breakdown(
+            outputs
+                .iter()
+                .map(|file| file.path.as_str())
+                .zip(counts.iter().copied())
+                .collect(),
+        );
+    }
+    if let Some(counts) = file_counts.as_ref().filter(|_| args.lang_summary) {
+        print_lang_summary(
+            outputs
+                .iter()
+                .map(|file| file.language.as_str())
+                .zip(counts.iter().copied()),
+        );
+    }
+
+    if let Some(dir) = &args.split_dir {
+        for file in &outputs {
+            let path = split_path(dir, &file.path);
+            write_output_file(&path, render_markdown(file, &render_options).as_bytes())?;
+        }
+        warn!("Wrote {} files to {}", outputs.len(), dir.display());
+        io::stdout().write_all(preamble.as_bytes())?;
+        return Ok(());
+    }
+    let Some(final_output) = final_output else {
+        return Ok(());
+    };
+
+    if args.gzip {
+        let compressed = gzip(final_output.as_bytes())?;
+        match &args.output {
+            Some(path) => write_output_file(path, &compressed)?,
+            None if !args.count_tokens => io::stdout().write_all(&compressed)?,
+            None => {}
+        }
+        return Ok(());
+    }
+
+    if let Some(path) = &args.output {
+        write_output_file(path, final_output.as_bytes())?;
+    }
+
+    if args.clipboard {
+        let bytes = final_output.len();
+        let tokens = token_count.unwrap_or(0);
+        copy_to_clipboard(final_output)?;
+        warn!("Copied {} bytes ({} tokens) to clipboard", bytes, tokens);
+    } else if args.output.is_none() && !args.count_tokens {
+        io::stdout().write_all(final_output.as_bytes())?;
     }
 
     Ok(())
@@ -479,9 +1880,276 @@ fn main() -> io::Result<()> {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    fn config(text: &str) -> toml::Table {
+        text.parse().unwrap()
+    }
+
+    fn cli(args: &[&str]) -> Vec<OsString> {
+        std::iter::once("create-context")
+            .chain(args.iter().copied())
+            .map(OsString::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_config_to_args() {
+        let command = Args::command();
+        let table = config("max_tokens = 100\nexclude = [\"a\", \"b\"]\nraw = true\n");
+        let argv = config_to_args(&table, &command, &[]).unwrap();
+        assert_eq!(
+            argv,
+            ["--exclude=a", "--exclude=b", "--max-tokens=100", "--raw"]
+                .map(OsString::from)
+                .to_vec()
+        );
+
+        // Keys given on the command line, or conflicting with one, are skipped
+        let table = config("max_tokens = 100\nrelative_to = \".\"\n");
+        let cli_ids = ["max_tokens".to_string(), "absolute_paths".to_string()];
+        assert!(config_to_args(&table, &command, &cli_ids)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_config_to_args_errors() {
+        let command = Args::command();
+        for text in [
+            "no_such_key = true\n",
+            "raw = \"yes\"\n",
+            "max_tokens = { limit = 100 }\n",
+        ] {
+            let err = config_to_args(&config(text), &command, &[]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{text}");
+        }
+    }
+
+    #[test]
+    fn test_parse_args_precedence() {
+        let cwd = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            cwd.path().join(CONFIG_FILENAME),
+            "max_tokens = 100\nhead = 10\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join(CONFIG_FILENAME), "max_tokens = 200\n").unwrap();
+
+        let args = parse_args_from(cli(&[]), cwd.path()).unwrap();
+        assert_eq!((args.max_tokens, args.head), (Some(100), Some(10)));
+
+        // The --dir config beats the one in the current directory
+        let dir_arg = dir.path().to_str().unwrap();
+        let args = parse_args_from(cli(&["--dir", dir_arg]), cwd.path()).unwrap();
+        assert_eq!((args.max_tokens, args.head), (Some(200), Some(10)));
+
+        // The command line beats both
+        let args =
+            parse_args_from(cli(&["--dir", dir_arg, "--max-tokens", "5"]), cwd.path()).unwrap();
+        assert_eq!(args.max_tokens, Some(5));
+
+        // A config key conflicting with a command-line flag is dropped
+        fs::write(dir.path().join(CONFIG_FILENAME), "relative_to = \".\"\n").unwrap();
+        let args =
+            parse_args_from(cli(&["--dir", dir_arg, "--absolute-paths"]), cwd.path()).unwrap();
+        assert!(args.absolute_paths && args.relative_to.is_none());
+    }
 
     #[test]
     fn test_foobarg() {
         assert!("FOOBAR" == "foobar".to_uppercase());
     }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("10k"), Ok(10 * 1024));
+        assert_eq!(parse_size("2M"), Ok(2 * 1024 * 1024));
+        assert!(parse_size("3x").is_err());
+        assert!(parse_size("k").is_err());
+    }
+
+    #[test]
+    fn test_output_includes_tree() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("main.rs");
+        fs::write(&file, "fn main() {}\n").unwrap();
+        let files = [file];
+        let contexts = [TreeContext {
+            root: tmp.path(),
+            files: &files,
+            max_depth: None,
+            style: &UNICODE_TREE,
+            annotate: false,
+            bpe: None,
+        }];
+        let tree = tree_section(&contexts, None, false);
+        let content = "```rust\n// main.rs\nfn main() {}\n```\n\n";
+        let output = assemble_output(Some("Review this:"), &tree, content, Some("Thanks"));
+        assert_eq!(output, format!("Review this:\n{tree}{content}Thanks"));
+
+        // Token counts are taken over the assembled output, tree included
+        let bpe = load_tokenizer(Tokenizer::default()).unwrap();
+        assert!(
+            count_tokens(&bpe, &output)
+                > count_tokens(&bpe, content) + count_tokens(&bpe, "Review this: Thanks")
+        );
+    }
+
+    #[test]
+    fn test_tree_leaves_out_skipped_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let source = tmp.path().join("a.rs");
+        let blob = tmp.path().join("blob.rs");
+        fs::write(&source, "fn main() {}\n").unwrap();
+        fs::write(&blob, b"\0\0\0").unwrap();
+        let root_files = vec![vec![source.clone(), blob]];
+
+        let outputs = process_files(&root_files[0], &ProcessOptions::default(), false);
+        let root_files = emitted_root_files(&root_files, &outputs);
+        assert_eq!(root_files, [vec![source]]);
+        let tree = render_tree(&TreeContext {
+            root: tmp.path(),
+            files: &root_files[0],
+            max_depth: None,
+            style: &UNICODE_TREE,
+            annotate: false,
+            bpe: None,
+        });
+        assert!(tree.contains("a.rs") && !tree.contains("blob.rs"));
+    }
+
+    #[test]
+    fn test_tree_section_heading_and_fence() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("main.rs");
+        fs::write(&file, "").unwrap();
+        let files = [file];
+        let contexts = [TreeContext {
+            root: Path::new("."),
+            files: &files,
+            max_depth: None,
+            style: &UNICODE_TREE,
+            annotate: false,
+            bpe: None,
+        }];
+        let tree = render_tree(&contexts[0]);
+        assert_eq!(
+            tree_section(&contexts, None, false),
+            format!("Directory Structure:\n\n```text\n{tree}\n```\n\n")
+        );
+        assert_eq!(
+            tree_section(&contexts, Some("## Files in {root}"), true),
+            format!("## Files in .\n\n{tree}\n\n")
+        );
+        assert_eq!(
+            tree_section(&contexts, Some(""), true),
+            format!("{tree}\n\n")
+        );
+    }
+
+    #[test]
+    fn test_apply_order() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        for name in ["a.rs", "b.rs", "c.rs", "main.rs"] {
+            fs::write(root.join(name), "").unwrap();
+        }
+        let mut files: Vec<PathBuf> = ["a.rs", "b.rs", "c.rs", "main.rs"]
+            .iter()
+            .map(|name| root.join(name))
+            .collect();
+        let order = [
+            root.join("c.rs"),
+            root.join("gone.rs"),
+            root.join(".").join("main.rs"),
+        ];
+        let unmatched = apply_order(&mut files, &order);
+        assert_eq!(unmatched, [&root.join("gone.rs")]);
+        assert_eq!(
+            files,
+            ["c.rs", "main.rs", "a.rs", "b.rs"].map(|name| root.join(name))
+        );
+
+        // --list follows the same order
+        let configs = [Config {
+            dir: root.to_path_buf(),
+            ..Config::default()
+        }];
+        let root_files = [["a.rs", "b.rs", "c.rs", "main.rs"]
+            .map(|name| root.join(name))
+            .to_vec()];
+        assert_eq!(
+            listed_in_order(&configs, &root_files, &files),
+            ["c.rs", "main.rs", "a.rs", "b.rs"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn test_summary_section() {
+        let file = |path: &str, language: &str, content: &str| ProcessedFile {
+            path: path.to_string(),
+            source: PathBuf::from(path),
+            language: language.to_string(),
+            content: content.to_string(),
+            git_status: None,
+            modified: None,
+            size: content.len() as u64,
+            sha256: None,
+        };
+        let files = [
+            file("src/main.rs", "rust", "fn main() {}\n"),
+            file("src/lib.rs", "rust", "\n"),
+            file("Cargo.toml", "toml", "[package]\n"),
+        ];
+        let listed = ["src/main.rs", "src/lib.rs", "Cargo.toml"].map(PathBuf::from);
+        assert_eq!(
+            summary_section(&files, Some(&[5, 1, 2]), &listed),
+            "## Overview\n\n- 3 files, 3 lines, 24 bytes, 8 tokens\n\
+             - Languages: rust (2 files, 6 tokens), toml (1 file, 2 tokens)\n\
+             - Top-level directories: src\n\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_pattern_lines() {
+        let text = "# Sources\nsrc/**/*.rs\n\n  tests/*.rs  \n!keep.log\n";
+        assert_eq!(
+            parse_pattern_lines(text),
+            ["src/**/*.rs", "tests/*.rs", "!keep.log"]
+        );
+    }
+
+    #[test]
+    fn test_split_path() {
+        let dir = Path::new("out");
+        assert_eq!(
+            split_path(dir, "src/main.rs"),
+            Path::new("out/src/main.rs.md")
+        );
+        assert_eq!(
+            split_path(dir, "../api/lib.rs"),
+            Path::new("out/api/lib.rs.md")
+        );
+        assert_eq!(
+            split_path(dir, "/abs/build.rs"),
+            Path::new("out/abs/build.rs.md")
+        );
+    }
+
+    #[test]
+    fn test_parse_lang_mapping() {
+        assert_eq!(
+            parse_lang_mapping(".ino=cpp"),
+            Ok((".ino".to_string(), "cpp".to_string()))
+        );
+        assert_eq!(
+            parse_lang_mapping("SConstruct = python"),
+            Ok(("SConstruct".to_string(), "python".to_string()))
+        );
+        assert!(parse_lang_mapping(".ino").is_err());
+        assert!(parse_lang_mapping("=cpp").is_err());
+    }
 }
@@ -0,0 +1,268 @@
+// ./src/patterns.rs
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Splits the inside of a brace group on commas that are not nested in
+/// another group.
+fn split_alternatives(inner: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(&inner[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&inner[start..]);
+    parts
+}
+
+/// Expands shell-style brace groups, e.g. `*.{rs,toml}` becomes `*.rs` and
+/// `*.toml`. Nested and multiple groups are supported; an unbalanced `{` is
+/// left as is.
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let mut depth = 0;
+    let mut open = 0;
+    for (i, c) in pattern.char_indices() {
+        match c {
+            '{' => {
+                if depth == 0 {
+                    open = i;
+                }
+                depth += 1;
+            }
+            '}' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    let (prefix, suffix) = (&pattern[..open], &pattern[i + 1..]);
+                    return split_alternatives(&pattern[open + 1..i])
+                        .into_iter()
+                        .flat_map(|alt| expand_braces(&format!("{prefix}{alt}{suffix}")))
+                        .collect();
+                }
+            }
+            _ => {}
+        }
+    }
+    vec![pattern.to_string()]
+}
+
+/// Compiles one glob. Brace groups are expanded first, and a leading `./` is
+/// dropped so `./src/*.rs` behaves like `src/*.rs`. As in ripgrep and
+/// gitignore files, `*` and `?` never match `/`; only `**` crosses
+/// directories.
+fn compile_glob(glob: &str) -> Vec<Result<Glob, globset::Error>> {
+    expand_braces(glob)
+        .iter()
+        .map(|expanded| {
+            GlobBuilder::new(expanded.strip_prefix("./").unwrap_or(expanded))
+                .literal_separator(true)
+                .build()
+        })
+        .collect()
+}
+
+/// Include globs compiled into a single matcher for globs relative to `--dir`
+/// and another for absolute globs.
+#[derive(Clone, Debug, Default)]
+pub struct PatternSet {
+    relative: GlobSet,
+    absolute: GlobSet,
+}
+
+impl PatternSet {
+    pub fn is_empty(&self) -> bool {
+        self.relative.is_empty() && self.absolute.is_empty()
+    }
+}
+
+fn build_set(globs: &[Glob]) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(glob.clone());
+    }
+    builder.build().unwrap_or_else(|e| {
+        warn!("Invalid glob patterns: {}", e);
+        GlobSet::empty()
+    })
+}
+
+/// Compiles glob patterns, reporting and skipping any that are invalid.
+pub fn compile_patterns(patterns: &[String]) -> PatternSet {
+    let (mut relative, mut absolute) = (Vec::new(), Vec::new());
+    for p in patterns {
+        for result in compile_glob(p) {
+            match result {
+                Ok(glob) if Path::new(glob.glob()).is_absolute() => absolute.push(glob),
+                Ok(glob) => relative.push(glob),
+                Err(e) => warn!("Invalid glob pattern '{}': {}", p, e.kind()),
+            }
+        }
+    }
+    PatternSet {
+        relative: build_set(&relative),
+        absolute: build_set(&absolute),
+    }
+}
+
+/// Returns an error for the first glob that does not compile, for callers
+/// that would rather fail than have it skipped.
+pub fn check_patterns<'a>(patterns: impl IntoIterator<Item = &'a str>) -> io::Result<()> {
+    for p in patterns {
+        if let Some(Err(e)) = compile_glob(p).into_iter().find(Result::is_err) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid glob pattern '{}': {}", p, e.kind()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// A compiled exclude glob. Negated globs (written with a leading `!`)
+/// re-include paths excluded by an earlier glob.
+pub struct ExcludeRule {
+    pattern: PatternSet,
+    negated: bool,
+}
+
+/// Compiles exclude globs, keeping their order so later globs override
+/// earlier ones as in a gitignore file.
+pub fn compile_excludes(patterns: &[String]) -> Vec<ExcludeRule> {
+    let mut rules = Vec::new();
+    for p in patterns {
+        let (negated, glob) = match p.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, p.as_str()),
+        };
+        let pattern = compile_patterns(&[glob.to_string()]);
+        if !pattern.is_empty() {
+            rules.push(ExcludeRule { pattern, negated });
+        }
+    }
+    rules
+}
+
+/// Returns true if the last exclude glob matching `path` is not negated.
+pub fn is_excluded_by(
+    rules: &[ExcludeRule],
+    path: &Path,
+    base_dir: &Path,
+    canonical_base: &Path,
+) -> bool {
+    rules
+        .iter()
+        .rev()
+        .find(|rule| path_matches(&rule.pattern, path, base_dir, canonical_base))
+        .is_some_and(|rule| !rule.negated)
+}
+
+/// Returns `path` relative to the walk root, trying both the root as given
+/// and its canonical form (explicitly listed files are canonicalized).
+pub fn relative_path<'a>(path: &'a Path, base_dir: &Path, canonical_base: &Path) -> &'a Path {
+    path.strip_prefix(base_dir)
+        .or_else(|_| path.strip_prefix(canonical_base))
+        .unwrap_or(path)
+}
+
+/// Returns canonical `path` relative to canonical `base`, climbing out with
+/// `..` when it is not under it, e.g. `../other/o.rs`.
+pub fn dotdot_relative(path: &Path, base: &Path) -> PathBuf {
+    let common = path
+        .components()
+        .zip(base.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative: PathBuf = base.components().skip(common).map(|_| "..").collect();
+    relative.extend(path.components().skip(common));
+    relative
+}
+
+/// Tests `path` against glob patterns. Relative patterns are matched against
+/// the path relative to `--dir`; absolute patterns are matched against the
+/// file's canonical absolute path.
+pub fn path_matches(
+    patterns: &PatternSet,
+    path: &Path,
+    base_dir: &Path,
+    canonical_base: &Path,
+) -> bool {
+    if patterns
+        .relative
+        .is_match(relative_path(path, base_dir, canonical_base))
+    {
+        return true;
+    }
+    !patterns.absolute.is_empty()
+        && patterns
+            .absolute
+            .is_match(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_braces() {
+        assert_eq!(expand_braces("**/*.{rs,toml}"), ["**/*.rs", "**/*.toml"]);
+        assert_eq!(
+            expand_braces("{src,tests}/*.{rs,md}"),
+            ["src/*.rs", "src/*.md", "tests/*.rs", "tests/*.md"]
+        );
+        assert_eq!(expand_braces("a{b,{c,d}e}"), ["ab", "ace", "ade"]);
+        assert_eq!(expand_braces("a{b"), ["a{b"]);
+    }
+
+    #[test]
+    fn test_check_patterns() {
+        assert!(check_patterns(["**/*.rs", "src/{a,b}.rs"]).is_ok());
+        assert!(check_patterns(["**/*.rs", "src/[ab.rs"]).is_err());
+        assert!(check_patterns(["{src,[}/*.rs"]).is_err());
+    }
+
+    #[test]
+    fn test_star_does_not_cross_directories() {
+        let patterns = compile_patterns(&[
+            "*.rs".to_string(),
+            "src/**/*.toml".to_string(),
+            "/etc/*.conf".to_string(),
+        ]);
+        let base = Path::new("/repo");
+        let matches = |p: &str| path_matches(&patterns, &base.join(p), base, base);
+
+        assert!(matches("main.rs"));
+        assert!(!matches("src/main.rs"));
+        assert!(matches("src/Cargo.toml"));
+        assert!(matches("src/a/b/Cargo.toml"));
+        assert!(!matches("Cargo.toml"));
+        assert!(path_matches(
+            &patterns,
+            Path::new("/etc/app.conf"),
+            base,
+            base
+        ));
+    }
+
+    #[test]
+    fn test_exclude_negation() {
+        let rules = compile_excludes(&["*.log".to_string(), "!keep.log".to_string()]);
+        let base = Path::new("/repo");
+        let excluded = |p: &str| is_excluded_by(&rules, &base.join(p), base, base);
+
+        assert!(excluded("debug.log"));
+        assert!(!excluded("keep.log"));
+        assert!(!excluded("main.rs"));
+
+        // A later glob wins over an earlier negation
+        let rules = compile_excludes(&["!keep.log".to_string(), "*.log".to_string()]);
+        assert!(is_excluded_by(&rules, &base.join("keep.log"), base, base));
+    }
+}
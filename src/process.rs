@@ -0,0 +1,1077 @@
+// ./src/process.rs
+use crate::git::GitStatus;
+use crate::language::{comment_markers, determine_language_with, mime_type, LanguageMap};
+use crate::patterns::dotdot_relative;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, SecondsFormat, Utc};
+use encoding_rs::Encoding;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Returns the byte length of the comment, string literal or char literal at
+/// the start of `s`, or `None` if it starts with none of these. Raw strings
+/// and nested block comments are understood; unterminated ones run to the
+/// end of `s`.
+fn rust_skip_len(s: &str) -> Option<usize> {
+    if s.starts_with("//") {
+        return Some(s.find('\n').unwrap_or(s.len()));
+    }
+    if s.starts_with("/*") {
+        let mut depth = 0;
+        let mut i = 0;
+        while i < s.len() {
+            if s[i..].starts_with("/*") {
+                depth += 1;
+                i += 2;
+            } else if s[i..].starts_with("*/") {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    return Some(i);
+                }
+            } else {
+                i += s[i..].chars().next().unwrap().len_utf8();
+            }
+        }
+        return Some(s.len());
+    }
+    if s.starts_with('"') {
+        let mut chars = s.char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                '"' => return Some(i + 1),
+                _ => {}
+            }
+        }
+        return Some(s.len());
+    }
+    if let Some(rest) = s.strip_prefix('r') {
+        // r"...", r#"..."#, and so on; `r#ident` is a raw identifier
+        let hashes = rest.len() - rest.trim_start_matches('#').len();
+        if !rest[hashes..].starts_with('"') {
+            return None;
+        }
+        let close = format!("\"{}", "#".repeat(hashes));
+        let body = hashes + 2;
+        let end = s[body..].find(&close).map(|n| body + n + close.len());
+        return Some(end.unwrap_or(s.len()));
+    }
+    if s.starts_with('\'') {
+        return char_literal_len(s);
+    }
+    None
+}
+
+/// Given the index of an opening `{` or `[` in `s`, returns the index just
+/// past its matching `}` or `]`, or the end of `s` if it is never closed.
+/// Delimiters inside comments, strings and char literals are ignored.
+fn block_end(s: &str, open: usize) -> usize {
+    let (open_ch, close_ch) = if s[open..].starts_with('[') {
+        ('[', ']')
+    } else {
+        ('{', '}')
+    };
+    let mut depth = 0;
+    let mut i = open;
+    while i < s.len() {
+        if let Some(skip) = rust_skip_len(&s[i..]) {
+            i += skip;
+            continue;
+        }
+        let ch = s[i..].chars().next().unwrap();
+        i += ch.len_utf8();
+        if ch == open_ch {
+            depth += 1;
+        } else if ch == close_ch {
+            depth -= 1;
+            if depth == 0 {
+                return i;
+            }
+        }
+    }
+    s.len()
+}
+
+/// Returns the index of the first `{` in `s` outside comments and literals,
+/// or `None` if a `;` comes first.
+fn body_start(s: &str) -> Option<usize> {
+    let mut i = 0;
+    while i < s.len() {
+        if let Some(skip) = rust_skip_len(&s[i..]) {
+            i += skip;
+            continue;
+        }
+        let ch = s[i..].chars().next().unwrap();
+        match ch {
+            '{' => return Some(i),
+            ';' => return None,
+            _ => i += ch.len_utf8(),
+        }
+    }
+    None
+}
+
+/// Returns the length of the outer attribute (`#[...]`) at the start of `s`.
+fn attribute_len(s: &str) -> Option<usize> {
+    let rest = s.strip_prefix('#')?;
+    let open = s.len() - rest.trim_start().len();
+    s[open..].starts_with('[').then(|| block_end(s, open))
+}
+
+/// Matches a function marked `#[test]` at the start of `s`, together with the
+/// other attributes around the marker, and returns the length up to the end
+/// of the function body.
+fn test_fn_len(s: &str) -> Option<usize> {
+    let mut i = 0;
+    let mut is_test = false;
+    while let Some(len) = attribute_len(&s[i..]) {
+        let attribute: String = s[i..i + len].split_whitespace().collect();
+        is_test |= attribute == "#[test]";
+        i += len;
+        i = s.len() - s[i..].trim_start().len();
+    }
+    if !is_test {
+        return None;
+    }
+
+    let mut rest = &s[i..];
+    while let Some(after) = ["pub", "async", "unsafe", "const"].iter().find_map(|q| {
+        rest.strip_prefix(q)
+            .filter(|r| r.starts_with(char::is_whitespace))
+    }) {
+        rest = after.trim_start();
+    }
+    if !rest.strip_prefix("fn")?.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let fn_start = s.len() - rest.len();
+    Some(block_end(s, fn_start + body_start(rest)?))
+}
+
+/// Matches a `#[cfg(test)]` attribute at the start of `s`, allowing
+/// whitespace between its tokens, and returns its length.
+fn cfg_test_len(s: &str) -> Option<usize> {
+    let mut rest = s.strip_prefix('#')?;
+    for token in ["[", "cfg", "(", "test", ")", "]"] {
+        rest = rest.trim_start().strip_prefix(token)?;
+    }
+    Some(s.len() - rest.len())
+}
+
+/// Matches `mod <name>` at the start of `s`, after any whitespace, and
+/// returns the index of the `{` or `;` that follows it.
+fn mod_item_len(s: &str) -> Option<usize> {
+    let rest = s.trim_start().strip_prefix("mod")?;
+    let name = rest.trim_start();
+    let name_len = name
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(name.len());
+    if name.len() == rest.len() || name_len == 0 {
+        return None;
+    }
+    let tail = name[name_len..].trim_start();
+    tail.starts_with(['{', ';']).then_some(s.len() - tail.len())
+}
+
+/// Matches `#[cfg(test)]` followed by a module item at the start of `s`,
+/// allowing further attributes and a `pub` or `pub(...)` visibility in
+/// between, and returns the index of the `{` or `;` after the module name.
+fn cfg_test_mod_len(s: &str) -> Option<usize> {
+    let mut i = cfg_test_len(s)?;
+    loop {
+        i = s.len() - s[i..].trim_start().len();
+        match attribute_len(&s[i..]) {
+            Some(len) => i += len,
+            None => break,
+        }
+    }
+    if let Some(rest) = s[i..].strip_prefix("pub") {
+        let rest = rest.trim_start();
+        i = s.len() - rest.len();
+        if rest.starts_with('(') {
+            i += rest.find(')')? + 1;
+        }
+    }
+    Some(i + mod_item_len(&s[i..])?)
+}
+
+/// Strips out every `#[cfg(test)] mod <name> { ... }` block, any
+/// `#[cfg(test)] mod <name>;` declaration, and every `#[test]` function
+/// wherever it appears, from the given Rust source.
+fn strip_rust_tests(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        let rest = &s[i..];
+        // Copy comments and literals whole so nothing inside them matches
+        if let Some(skip) = rust_skip_len(rest) {
+            result.push_str(&rest[..skip]);
+            i += skip;
+            continue;
+        }
+        if let Some(len) = cfg_test_mod_len(rest) {
+            i += len;
+            // Skip the module's block, or just the `;` of a declaration
+            i = if s[i..].starts_with('{') {
+                block_end(s, i)
+            } else {
+                i + 1
+            };
+            continue;
+        }
+        if let Some(len) = test_fn_len(rest) {
+            i += len;
+            continue;
+        }
+        let ch = rest.chars().next().unwrap();
+        result.push(ch);
+        i += ch.len_utf8();
+    }
+    result
+}
+
+/// Replaces the body of every function in the given Rust source with
+/// `{ /* ... */ }`, keeping signatures, types, consts and the rest of each
+/// item. Methods in `impl` and `trait` blocks are reduced the same way, and
+/// `fn` inside comments or literals is left alone.
+fn rust_skeleton(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        let rest = &s[i..];
+        if let Some(skip) = rust_skip_len(rest) {
+            result.push_str(&rest[..skip]);
+            i += skip;
+            continue;
+        }
+        // `fn` as a keyword, not part of an identifier or a `fn(..)` type
+        let after_ident = result.ends_with(|c: char| c.is_alphanumeric() || c == '_');
+        let is_fn = !after_ident
+            && rest
+                .strip_prefix("fn")
+                .is_some_and(|r| r.starts_with(char::is_whitespace));
+        if let Some(open) = is_fn.then(|| body_start(rest)).flatten() {
+            result.push_str(&rest[..open]);
+            result.push_str("{ /* ... */ }");
+            i = block_end(s, i + open);
+            continue;
+        }
+        let ch = rest.chars().next().unwrap();
+        result.push(ch);
+        i += ch.len_utf8();
+    }
+    result
+}
+
+/// A file that has been read and preprocessed, ready to be rendered.
+#[derive(Clone, Debug)]
+pub struct ProcessedFile {
+    /// The path shown in the file's header.
+    pub path: String,
+    /// The path the file was read from, as collected.
+    pub source: PathBuf,
+    pub language: String,
+    pub content: String,
+    /// The file's git state for `--git-status`, e.g. `modified`. `None` for
+    /// clean files or when git status is not requested.
+    pub git_status: Option<&'static str>,
+    /// Last modification time as ISO-8601 in UTC, e.g.
+    /// `2024-05-01T09:30:00Z`, when `show_mtime` is set.
+    pub modified: Option<String>,
+    /// Size of the file on disk, in bytes.
+    pub size: u64,
+    /// Hex SHA-256 of the file's bytes on disk, when `hash_contents` is set.
+    pub sha256: Option<String>,
+}
+
+/// Options controlling how each file is read and transformed.
+#[derive(Clone, Debug)]
+pub struct ProcessOptions {
+    pub ignore_tests: bool,
+    pub max_file_size: Option<u64>,
+    pub skip_binary: bool,
+    pub line_numbers: bool,
+    pub strip_comments: bool,
+    /// Replace function bodies with `{ /* ... */ }` in Rust files, leaving
+    /// an outline of the API.
+    pub skeleton: bool,
+    /// Include binary files as base64 instead of skipping them.
+    pub base64_binary: bool,
+    /// Keep only this many lines of each file.
+    pub head: Option<usize>,
+    /// Annotate each file with its state in this git status.
+    pub git_status: Option<GitStatus>,
+    /// Annotate each file with its last modification time.
+    pub show_mtime: bool,
+    /// Languages for file names and extensions the built-in tables miss.
+    pub lang_map: LanguageMap,
+    /// Convert `\r\n` and lone `\r` line endings to `\n`.
+    pub normalize_eol: bool,
+    /// Collapse runs of blank lines into one.
+    pub squeeze_blank: bool,
+    /// Remove spaces and tabs at the end of each line.
+    pub trim_trailing_whitespace: bool,
+    /// Show header paths relative to this canonical directory instead of as
+    /// collected. Files outside it are shown by their absolute path.
+    pub relative_to: Option<PathBuf>,
+    /// Without `relative_to`, show header paths relative to this canonical
+    /// directory, usually `--dir`. Files outside it climb out with `..`.
+    pub base_dir: Option<PathBuf>,
+    /// Hash each file's bytes as it is read, for `ProcessedFile::sha256`.
+    pub hash_contents: bool,
+}
+
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        ProcessOptions {
+            ignore_tests: false,
+            max_file_size: None,
+            skip_binary: true,
+            line_numbers: false,
+            strip_comments: false,
+            skeleton: false,
+            base64_binary: false,
+            head: None,
+            git_status: None,
+            show_mtime: false,
+            lang_map: LanguageMap::default(),
+            normalize_eol: false,
+            squeeze_blank: false,
+            trim_trailing_whitespace: false,
+            relative_to: None,
+            base_dir: None,
+            hash_contents: false,
+        }
+    }
+}
+
+/// A step that rewrites a file's content.
+type Transform<'a> = Box<dyn Fn(&str) -> String + 'a>;
+
+impl ProcessOptions {
+    /// The content transforms these options enable for a file in `language`,
+    /// in the order they are applied.
+    fn transforms<'a>(&self, language: &'a str) -> Vec<Transform<'a>> {
+        let mut steps: Vec<Transform> = Vec::new();
+        // Normalize first so every later step sees `\n` line endings
+        if self.normalize_eol {
+            steps.push(Box::new(normalize_eol));
+        }
+        if self.ignore_tests && language == "rust" {
+            steps.push(Box::new(strip_rust_tests));
+        }
+        if self.skeleton && language == "rust" {
+            steps.push(Box::new(rust_skeleton));
+        }
+        if self.strip_comments {
+            steps.push(Box::new(move |content| strip_comments(content, language)));
+        }
+        if self.trim_trailing_whitespace {
+            steps.push(Box::new(trim_trailing_whitespace));
+        }
+        if self.squeeze_blank {
+            steps.push(Box::new(squeeze_blank));
+        }
+        steps
+    }
+}
+
+/// Returns the byte length of a Rust char literal at the start of `s`, if
+/// there is one. This distinguishes `'x'` and `'\n'` from lifetimes like `'a`.
+fn char_literal_len(s: &str) -> Option<usize> {
+    let mut chars = s.char_indices().skip(1);
+    let (_, c) = chars.next()?;
+    if c == '\\' {
+        // Escapes run until the closing quote, e.g. '\n' or '\u{1F600}'
+        let (_, escaped) = chars.next()?;
+        let start = 2 + escaped.len_utf8();
+        let close = s[start..].find(['\'', '\n'])?;
+        return s[start + close..]
+            .starts_with('\'')
+            .then_some(start + close + 1);
+    }
+    let (j, q) = chars.next()?;
+    (q == '\'').then_some(j + 1)
+}
+
+/// Removes line and block comments using the markers for `language`. String
+/// literals are tracked on a best-effort basis so comment-like sequences
+/// inside them survive. Lines left empty by the removal are dropped.
+fn strip_comments(content: &str, language: &str) -> String {
+    let (line_marker, block_markers) = comment_markers(language);
+    if line_marker.is_none() && block_markers.is_none() {
+        return content.to_string();
+    }
+    // Rust uses `'` for lifetimes as well as char literals
+    let quotes: &[char] = if language == "rust" {
+        &['"']
+    } else {
+        &['"', '\'']
+    };
+
+    let mut out = String::with_capacity(content.len());
+    let mut i = 0;
+    let mut line_start = 0;
+    let mut line_had_comment = false;
+    let mut in_block = false;
+    let mut in_string: Option<char> = None;
+
+    // Keep a shebang line intact even though it looks like a `#` comment
+    if content.starts_with("#!") {
+        i = content.find('\n').map_or(content.len(), |n| n + 1);
+        out.push_str(&content[..i]);
+        line_start = out.len();
+    }
+
+    let finish_line = |out: &mut String, line_start: usize, had_comment: bool| {
+        if had_comment {
+            let trimmed = out[line_start..].trim_end_matches([' ', '\t']).len();
+            out.truncate(line_start + trimmed);
+        }
+    };
+
+    while i < content.len() {
+        let rest = &content[i..];
+        let ch = rest.chars().next().unwrap();
+
+        if ch == '\n' {
+            finish_line(&mut out, line_start, line_had_comment);
+            if line_had_comment && out.len() == line_start {
+                // The line held nothing but comments
+            } else {
+                out.push('\n');
+            }
+            line_start = out.len();
+            line_had_comment = in_block;
+            i += 1;
+            continue;
+        }
+
+        if in_block {
+            let (_, end) = block_markers.unwrap();
+            if rest.starts_with(end) {
+                in_block = false;
+                i += end.len();
+            } else {
+                i += ch.len_utf8();
+            }
+            continue;
+        }
+
+        if let Some(quote) = in_string {
+            out.push(ch);
+            i += ch.len_utf8();
+            if ch == '\\' {
+                if let Some(next) = content[i..].chars().next().filter(|&c| c != '\n') {
+                    out.push(next);
+                    i += next.len_utf8();
+                }
+            } else if ch == quote {
+                in_string = None;
+            }
+            continue;
+        }
+
+        if let Some(marker) = line_marker {
+            // `#` only starts a comment at a word boundary, so `$#` and `${#x}` survive
+            let at_boundary = marker != "#"
+                || content[..i]
+                    .chars()
+                    .next_back()
+                    .is_none_or(char::is_whitespace);
+            if at_boundary && rest.starts_with(marker) {
+                i += rest.find('\n').unwrap_or(rest.len());
+                line_had_comment = true;
+                continue;
+            }
+        }
+
+        if let Some((start, _)) = block_markers {
+            if rest.starts_with(start) {
+                in_block = true;
+                line_had_comment = true;
+                i += start.len();
+                continue;
+            }
+        }
+
+        if language == "rust" && ch == '\'' {
+            if let Some(len) = char_literal_len(rest) {
+                out.push_str(&rest[..len]);
+                i += len;
+                continue;
+            }
+        }
+
+        if quotes.contains(&ch) {
+            in_string = Some(ch);
+        }
+        out.push(ch);
+        i += ch.len_utf8();
+    }
+    finish_line(&mut out, line_start, line_had_comment);
+
+    out
+}
+
+/// Prefixes each line with its line number, right-aligned to the width of
+/// the largest number.
+fn number_lines(content: &str) -> String {
+    let total = content.lines().count();
+    let width = total.to_string().len();
+    let mut out = String::with_capacity(content.len() + total * (width + 3));
+    for (i, line) in content.lines().enumerate() {
+        out.push_str(&format!("{:>width$} | {}\n", i + 1, line));
+    }
+    out
+}
+
+/// Keeps the first `n` lines of `content`, returning them along with the
+/// number of lines that were cut.
+fn head_lines(content: &str, n: usize) -> (String, usize) {
+    let total = content.lines().count();
+    if total <= n {
+        return (content.to_string(), 0);
+    }
+    let end = match n {
+        0 => 0,
+        _ => content
+            .match_indices('\n')
+            .nth(n - 1)
+            .map_or(content.len(), |(i, _)| i + 1),
+    };
+    (content[..end].to_string(), total - n)
+}
+
+/// Converts Windows (`\r\n`) and classic Mac (`\r`) line endings to `\n`.
+fn normalize_eol(content: &str) -> String {
+    content.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// Removes spaces and tabs at the end of each line, keeping line endings.
+fn trim_trailing_whitespace(content: &str) -> String {
+    let mut out = String::with_capacity(content.len());
+    for line in content.split_inclusive('\n') {
+        let (text, eol) = line.split_at(line.trim_end_matches(['\r', '\n']).len());
+        out.push_str(text.trim_end_matches([' ', '\t']));
+        out.push_str(eol);
+    }
+    out
+}
+
+/// Collapses each run of blank (or whitespace-only) lines down to its first
+/// line. This is purely line-based, so it also applies inside multi-line
+/// string literals.
+fn squeeze_blank(content: &str) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut prev_blank = false;
+    for line in content.split_inclusive('\n') {
+        let blank = line.trim().is_empty();
+        if !(blank && prev_blank) {
+            out.push_str(line);
+        }
+        prev_blank = blank;
+    }
+    out
+}
+
+/// Number of leading bytes inspected when sniffing for binary content.
+const BINARY_SNIFF_LEN: usize = 8192;
+
+/// Treats a file as binary if a NUL byte appears in its first few KB.
+fn is_binary(bytes: &[u8]) -> bool {
+    bytes[..bytes.len().min(BINARY_SNIFF_LEN)].contains(&0)
+}
+
+/// Decodes file contents to a string. A byte order mark selects UTF-8 or
+/// UTF-16 (LE or BE) and is stripped; without one the file must be UTF-8. On
+/// failure, returns the name of the encoding that was tried.
+fn decode(bytes: Vec<u8>) -> Result<String, &'static str> {
+    match Encoding::for_bom(&bytes) {
+        Some((encoding, bom_len)) => {
+            let (text, had_errors) = encoding.decode_without_bom_handling(&bytes[bom_len..]);
+            if had_errors {
+                Err(encoding.name())
+            } else {
+                Ok(text.into_owned())
+            }
+        }
+        None => String::from_utf8(bytes).map_err(|_| "UTF-8"),
+    }
+}
+
+/// The path shown in a file's header: relative to `relative_to` when it is
+/// set, else relative to `base_dir`, else as collected.
+fn display_path(file_path: &Path, relative_to: Option<&Path>, base_dir: Option<&Path>) -> String {
+    let absolute = || {
+        file_path
+            .canonicalize()
+            .unwrap_or_else(|_| file_path.to_path_buf())
+    };
+    let Some(base) = relative_to else {
+        return match base_dir {
+            Some(base) => dotdot_relative(&absolute(), base),
+            None => file_path.to_path_buf(),
+        }
+        .to_string_lossy()
+        .into_owned();
+    };
+    let absolute = absolute();
+    match absolute.strip_prefix(base) {
+        Ok(relative) => relative.to_string_lossy().into_owned(),
+        Err(_) => {
+            warn!(
+                "Warning: '{}' is not under '{}'; showing its absolute path",
+                file_path.display(),
+                base.display()
+            );
+            absolute.to_string_lossy().into_owned()
+        }
+    }
+}
+
+/// Reads and transforms a single file. Files that are too large, binary,
+/// unreadable or cannot be decoded are reported on stderr and skipped.
+pub fn process_file(file_path: &Path, options: &ProcessOptions) -> Option<ProcessedFile> {
+    match try_process_file(file_path, options) {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Skipping {}", e);
+            None
+        }
+    }
+}
+
+/// Like `process_file`, but a binary, unreadable or undecodable file is an
+/// error instead of a warning. Files over `max_file_size` are still skipped
+/// with a warning, as asked.
+pub fn try_process_file(
+    file_path: &Path,
+    options: &ProcessOptions,
+) -> io::Result<Option<ProcessedFile>> {
+    let unreadable =
+        |e: io::Error| io::Error::new(e.kind(), format!("'{}': {}", file_path.display(), e));
+    if let Some(max_file_size) = options.max_file_size {
+        let size = fs::metadata(file_path).map_err(unreadable)?.len();
+        if size > max_file_size {
+            warn!(
+                "Skipping '{}': {} bytes exceeds the maximum file size of {} bytes",
+                file_path.display(),
+                size,
+                max_file_size
+            );
+            return Ok(None);
+        }
+    }
+
+    let bytes = fs::read(file_path).map_err(unreadable)?;
+    // UTF-16 text is full of NUL bytes, so only sniff files without a BOM
+    let has_bom = Encoding::for_bom(&bytes).is_some();
+    let binary = !has_bom && is_binary(&bytes);
+    if binary && options.skip_binary && !options.base64_binary {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("binary file '{}'", file_path.display()),
+        ));
+    }
+
+    let size = bytes.len() as u64;
+    let sha256 = options
+        .hash_contents
+        .then(|| format!("{:x}", Sha256::digest(&bytes)));
+    if binary && options.base64_binary {
+        // Content transforms would only mangle the encoding
+        let content = encode_base64(&bytes, mime_type(file_path));
+        return Ok(Some(finish_file(
+            file_path,
+            options,
+            "base64".to_string(),
+            content,
+            size,
+            sha256,
+        )));
+    }
+    let mut content = decode(bytes).map_err(|encoding| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("'{}': not valid {}", file_path.display(), encoding),
+        )
+    })?;
+    let language = determine_language_with(
+        &file_path.to_string_lossy(),
+        Some(&content),
+        &options.lang_map,
+    );
+
+    for transform in options.transforms(&language) {
+        content = transform(&content);
+    }
+
+    let mut truncated = 0;
+    if let Some(n) = options.head {
+        (content, truncated) = head_lines(&content, n);
+    }
+
+    if options.line_numbers {
+        content = number_lines(&content);
+    }
+
+    if truncated > 0 {
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(&format!("... (truncated, {} more lines)\n", truncated));
+    }
+
+    Ok(Some(finish_file(
+        file_path, options, language, content, size, sha256,
+    )))
+}
+
+/// Builds the `ProcessedFile` for final `content`, adding the display path
+/// and the annotations `options` ask for.
+fn finish_file(
+    file_path: &Path,
+    options: &ProcessOptions,
+    language: String,
+    content: String,
+    size: u64,
+    sha256: Option<String>,
+) -> ProcessedFile {
+    ProcessedFile {
+        path: display_path(
+            file_path,
+            options.relative_to.as_deref(),
+            options.base_dir.as_deref(),
+        ),
+        source: file_path.to_path_buf(),
+        language,
+        content,
+        git_status: options
+            .git_status
+            .as_ref()
+            .and_then(|status| status.state(file_path)),
+        modified: options
+            .show_mtime
+            .then(|| modified_time(file_path))
+            .flatten(),
+        size,
+        sha256,
+    }
+}
+
+/// Line length for base64-encoded files, as in MIME.
+const BASE64_LINE_LEN: usize = 76;
+
+/// Encodes a binary file as base64 in lines of `BASE64_LINE_LEN`, after a
+/// note giving its type and original size.
+fn encode_base64(bytes: &[u8], mime_type: &str) -> String {
+    let encoded = STANDARD.encode(bytes);
+    let mut content = format!(
+        "binary file: {}, {} bytes, base64-encoded\n",
+        mime_type,
+        bytes.len()
+    );
+    // Base64 is ASCII, so chunking the bytes never splits a character
+    for line in encoded.as_bytes().chunks(BASE64_LINE_LEN) {
+        content.push_str(std::str::from_utf8(line).unwrap_or_default());
+        content.push('\n');
+    }
+    content
+}
+
+/// Formats the file's last modification time as ISO-8601 in UTC, or `None`
+/// if the platform does not record it.
+fn modified_time(file_path: &Path) -> Option<String> {
+    let modified = fs::metadata(file_path).and_then(|m| m.modified()).ok()?;
+    Some(DateTime::<Utc>::from(modified).to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+/// Processes `files`, optionally in parallel. Skipped files are dropped and
+/// the rest keep the order of `files` either way.
+pub fn process_files(
+    files: &[PathBuf],
+    options: &ProcessOptions,
+    parallel: bool,
+) -> Vec<ProcessedFile> {
+    if parallel {
+        files
+            .par_iter()
+            .filter_map(|file_path| process_file(file_path, options))
+            .collect()
+    } else {
+        files
+            .iter()
+            .filter_map(|file_path| process_file(file_path, options))
+            .collect()
+    }
+}
+
+/// `process_files` using `try_process_file`, failing on the first file that
+/// cannot be read or decoded.
+pub fn try_process_files(
+    files: &[PathBuf],
+    options: &ProcessOptions,
+    parallel: bool,
+) -> io::Result<Vec<ProcessedFile>> {
+    let processed: Vec<Option<ProcessedFile>> = if parallel {
+        files
+            .par_iter()
+            .map(|file_path| try_process_file(file_path, options))
+            .collect::<io::Result<_>>()?
+    } else {
+        files
+            .iter()
+            .map(|file_path| try_process_file(file_path, options))
+            .collect::<io::Result<_>>()?
+    };
+    Ok(processed.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_eol() {
+        assert_eq!(normalize_eol("a\r\nb\rc\n\r\nd\r"), "a\nb\nc\n\nd\n");
+        assert_eq!(number_lines(&normalize_eol("x\ry\r\n")), "1 | x\n2 | y\n");
+    }
+
+    #[test]
+    fn test_trim_trailing_whitespace() {
+        assert_eq!(
+            trim_trailing_whitespace("a  \nb\t\r\n  \nc "),
+            "a\nb\r\n\nc"
+        );
+    }
+
+    #[test]
+    fn test_squeeze_blank() {
+        assert_eq!(squeeze_blank("a\n\n\n  \nb\n\nc\n\n\n"), "a\n\nb\n\nc\n\n");
+        assert_eq!(squeeze_blank("\n\nx"), "\nx");
+    }
+
+    #[test]
+    fn test_strip_rust_tests_free_functions() {
+        let source = "fn keep() {}
+
+#[test]
+fn top_level() { assert!(true); }
+
+#[cfg(test)]
+impl Foo {
+    fn helper(&self) {}
+
+    #[ignore]
+    #[ test ]
+    #[should_panic(expected = \"]\")]
+    async fn gated() -> Result<(), String> { Ok(()) }
+}
+
+#[inline]
+fn also_keep() {}
+";
+        assert_eq!(
+            strip_rust_tests(source),
+            "fn keep() {}\n\n\n\n#[cfg(test)]\nimpl Foo {\n    fn helper(&self) {}\n\n    \n}\n\n\
+             #[inline]\nfn also_keep() {}\n"
+        );
+    }
+
+    #[test]
+    fn test_strip_rust_tests_multiple_modules() {
+        let source = "fn a() {}
+#[cfg(test)]
+mod unit {
+    fn x() {}
+}
+fn b() {}
+# [ cfg( test ) ]
+
+mod integration_tests { fn y() {} }
+#[cfg(test)]
+mod fixtures;
+#[cfg(test)]
+fn helper() {}
+const S: &str = \"#[cfg(test)] mod z {\";
+#[cfg(test)]
+#[allow(dead_code)]
+mod with_attribute { fn w() {} }
+#[cfg(test)] pub(crate) mod visible { fn v() {} }
+#[cfg(test)] pub mod public;
+";
+        assert_eq!(
+            strip_rust_tests(source),
+            "fn a() {}\n\nfn b() {}\n\n\n#[cfg(test)]\nfn helper() {}\n\
+             const S: &str = \"#[cfg(test)] mod z {\";\n\n\n\n"
+        );
+    }
+
+    #[test]
+    fn test_strip_rust_tests_braces_in_literals() {
+        let source = r##"fn main() {}
+
+#[cfg(test)]
+mod tests {
+    // an unbalanced } in a comment
+    /* and { in a /* nested */ block comment */
+    #[test]
+    fn tricky() {
+        let a = "}";
+        let b = "\"}";
+        let c = r#"}"# ;
+        let d = '}';
+        let e = '{';
+        fn lifetime<'a>(x: &'a str) -> &'a str { x }
+    }
+}
+
+fn after() {}
+"##;
+        assert_eq!(
+            strip_rust_tests(source),
+            "fn main() {}\n\n\n\nfn after() {}\n"
+        );
+    }
+
+    #[test]
+    fn test_display_path_relative_to() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("sub").join("src")).unwrap();
+        let file = root.join("sub").join("src").join("lib.rs");
+        fs::write(&file, "").unwrap();
+
+        assert_eq!(display_path(&file, Some(&root), None), "sub/src/lib.rs");
+        assert_eq!(display_path(&file, None, None), file.to_string_lossy());
+        let elsewhere = root.join("sub").join("src").join("other");
+        assert_eq!(
+            display_path(&file, Some(&elsewhere), None),
+            file.to_string_lossy()
+        );
+        // Relative to --dir, a file outside it climbs out with `..`
+        assert_eq!(
+            display_path(&file, None, Some(&elsewhere)),
+            Path::new("..").join("lib.rs").to_string_lossy()
+        );
+        assert_eq!(
+            display_path(&file, Some(&elsewhere), Some(&root)),
+            file.to_string_lossy()
+        );
+    }
+
+    #[test]
+    fn test_rust_skeleton() {
+        let input = r#"/// Adds one.
+pub fn add_one(x: u32) -> u32 {
+    let s = "fn fake() { }";
+    if x > 0 { x + 1 } else { 1 }
+}
+
+impl Parser {
+    pub fn new(callback: fn(u32) -> u32) -> Self
+    where
+        Self: Sized,
+    {
+        Parser { callback }
+    }
+}
+
+trait Named {
+    fn name(&self) -> &str;
+    fn greet(&self) -> String { format!("hi {}", self.name()) }
+}
+
+const BRACES: &str = "}{";
+"#;
+        let expected = r#"/// Adds one.
+pub fn add_one(x: u32) -> u32 { /* ... */ }
+
+impl Parser {
+    pub fn new(callback: fn(u32) -> u32) -> Self
+    where
+        Self: Sized,
+    { /* ... */ }
+}
+
+trait Named {
+    fn name(&self) -> &str;
+    fn greet(&self) -> String { /* ... */ }
+}
+
+const BRACES: &str = "}{";
+"#;
+        assert_eq!(rust_skeleton(input), expected);
+    }
+
+    #[test]
+    fn test_encode_base64() {
+        assert_eq!(
+            encode_base64(b"\x89PNG\0", "image/png"),
+            "binary file: image/png, 5 bytes, base64-encoded\niVBORwA=\n"
+        );
+        let lines = encode_base64(&[0; 100], "application/octet-stream");
+        let lengths: Vec<usize> = lines.lines().skip(1).map(str::len).collect();
+        assert_eq!(lengths, [76, 60]);
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(b"fn main() {}".to_vec()).unwrap(), "fn main() {}");
+        assert_eq!(decode(b"\xEF\xBB\xBFlet x;".to_vec()).unwrap(), "let x;");
+        assert_eq!(decode(b"\xFF\xFEh\0i\0".to_vec()).unwrap(), "hi");
+        assert_eq!(decode(b"\xFE\xFF\0h\0i".to_vec()).unwrap(), "hi");
+        assert_eq!(decode(b"\xC3\x28".to_vec()), Err("UTF-8"));
+        assert_eq!(decode(b"\xFF\xFE\x00\xD8".to_vec()), Err("UTF-16LE"));
+    }
+
+    #[test]
+    fn test_head_lines() {
+        assert_eq!(head_lines("a\nb\nc\n", 2), ("a\nb\n".to_string(), 1));
+        assert_eq!(head_lines("a\nb\nc", 5), ("a\nb\nc".to_string(), 0));
+        assert_eq!(head_lines("a\nb\n", 0), (String::new(), 2));
+    }
+
+    #[test]
+    fn test_strip_comments() {
+        let src =
+            "// header\nlet url = \"http://x\"; // trailing\n/* block\n spans */let c = '\"';\n";
+        assert_eq!(
+            strip_comments(src, "rust"),
+            "let url = \"http://x\";\nlet c = '\"';\n"
+        );
+
+        let py = "#!/usr/bin/env python3\n# comment\nx = \"#not\"  # yes\n";
+        assert_eq!(
+            strip_comments(py, "python"),
+            "#!/usr/bin/env python3\nx = \"#not\"\n"
+        );
+    }
+
+    #[test]
+    fn test_try_process_file_errors() {
+        let tmp = tempfile::tempdir().unwrap();
+        let binary = tmp.path().join("logo.png");
+        fs::write(&binary, [0x89, b'P', b'N', b'G', 0, 0, 0, 0]).unwrap();
+        let missing = tmp.path().join("missing.rs");
+        let options = ProcessOptions::default();
+
+        for path in [&binary, &missing] {
+            assert!(process_file(path, &options).is_none());
+            assert!(try_process_file(path, &options).is_err());
+        }
+        let limited = ProcessOptions {
+            max_file_size: Some(1),
+            ..ProcessOptions::default()
+        };
+        assert!(try_process_file(&binary, &limited).unwrap().is_none());
+    }
+}
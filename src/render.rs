@@ -0,0 +1,487 @@
+// ./src/render.rs
+use crate::language::comment_syntax;
+use crate::process::ProcessedFile;
+use crate::tokens::count_tokens;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io;
+use tiktoken_rs::CoreBPE;
+
+/// Output formats
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Fenced markdown code blocks
+    #[default]
+    Markdown,
+    /// Fenced markdown code blocks, each inside a collapsible `<details>`
+    /// element summarized by the file's path
+    MarkdownCollapsible,
+    /// A JSON document with one object per file
+    Json,
+    /// One JSON object per line, streamed as each file is processed
+    Jsonl,
+    /// A `<document>` element per file inside a `<documents>` root
+    Xml,
+}
+
+impl Format {
+    /// Whether the format is one of the markdown variants.
+    pub fn is_markdown(self) -> bool {
+        matches!(self, Format::Markdown | Format::MarkdownCollapsible)
+    }
+}
+
+/// Options controlling how processed files are rendered into the output.
+#[derive(Clone, Debug, Default)]
+pub struct RenderOptions {
+    pub format: Format,
+    pub raw: bool,
+    /// A line written before each file, with `{path}` replaced by the file's
+    /// path, e.g. `=== FILE: {path} ===`. Markdown only.
+    pub separator: Option<String>,
+    /// Start with a table of contents linking to an anchor before each file.
+    /// Markdown only.
+    pub toc: bool,
+}
+
+/// Renders a processed file as a fenced markdown code block with a header
+/// comment naming the file. For languages without comments the header goes
+/// on its own line above the fence instead, so the block stays valid. With
+/// `Format::MarkdownCollapsible` the block is wrapped in a `<details>` element.
+pub fn render_markdown(file: &ProcessedFile, options: &RenderOptions) -> String {
+    render_markdown_block(file, options, None)
+}
+
+fn render_markdown_block(
+    file: &ProcessedFile,
+    options: &RenderOptions,
+    anchor: Option<&str>,
+) -> String {
+    let syntax = comment_syntax(&file.language);
+    let mut buf = String::new();
+    use std::fmt::Write;
+
+    if let Some(anchor) = anchor {
+        let _ = writeln!(buf, "<a name=\"{}\"></a>", anchor);
+    }
+    if let Some(separator) = &options.separator {
+        let _ = writeln!(buf, "{}", separator.replace("{path}", &file.path));
+    }
+    let collapsible = options.format == Format::MarkdownCollapsible;
+    if collapsible {
+        // The blank line lets markdown renderers parse the fence inside
+        let _ = writeln!(
+            buf,
+            "<details>\n<summary>{}</summary>\n",
+            escape_xml(&file.path)
+        );
+    }
+    let notes: Vec<&str> = file
+        .git_status
+        .into_iter()
+        .chain(file.modified.as_deref())
+        .collect();
+    let header = if notes.is_empty() {
+        file.path.clone()
+    } else {
+        format!("{} ({})", file.path, notes.join(", "))
+    };
+    if syntax.is_none() {
+        let _ = writeln!(buf, "{}", header);
+    }
+    if !options.raw {
+        let _ = writeln!(buf, "```{}", file.language);
+    }
+    match syntax {
+        Some((start, Some(end))) => {
+            let _ = writeln!(buf, "{} {} {}", start, header, end);
+        }
+        Some((start, None)) => {
+            let _ = writeln!(buf, "{} {}", start, header);
+        }
+        None => {}
+    }
+    buf.push_str(&file.content);
+    if options.raw {
+        if !file.content.ends_with('\n') {
+            buf.push('\n');
+        }
+        buf.push('\n');
+    } else {
+        buf.push_str("```\n\n");
+    }
+    if collapsible {
+        buf.push_str("</details>\n\n");
+    }
+
+    buf
+}
+
+/// Turns a path into an anchor name: lowercase ASCII letters and digits, with
+/// each run of other characters replaced by a single `-`.
+fn slugify(path: &str) -> String {
+    let mut slug = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let len = slug.trim_end_matches('-').len();
+    slug.truncate(len);
+    if slug.is_empty() {
+        slug.push_str("file");
+    }
+    slug
+}
+
+/// One anchor per file, in order. Paths that slugify the same, such as
+/// `a_b.rs` and `a-b.rs`, are told apart with a numeric suffix.
+fn anchors(files: &[ProcessedFile]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    files
+        .iter()
+        .map(|file| {
+            let slug = slugify(&file.path);
+            let mut anchor = slug.clone();
+            let mut n = 1;
+            while !seen.insert(anchor.clone()) {
+                n += 1;
+                anchor = format!("{slug}-{n}");
+            }
+            anchor
+        })
+        .collect()
+}
+
+/// The contents list `render_files` starts markdown output with when `toc`
+/// is set.
+pub fn table_of_contents(files: &[ProcessedFile]) -> String {
+    render_toc(files, &anchors(files))
+}
+
+/// Renders a markdown list linking each file to its anchor.
+fn render_toc(files: &[ProcessedFile], anchors: &[String]) -> String {
+    let mut toc = String::from("## Contents\n\n");
+    for (file, anchor) in files.iter().zip(anchors) {
+        toc.push_str(&format!("- [{}](#{})\n", file.path, anchor));
+    }
+    toc.push('\n');
+    toc
+}
+
+#[derive(Serialize)]
+struct JsonFile<'a> {
+    path: &'a str,
+    language: &'a str,
+    content: &'a str,
+    token_count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    git_status: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct JsonOutput<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tree: Option<String>,
+    files: Vec<JsonFile<'a>>,
+}
+
+/// JSON Schema for the `Format::Json` document, for `--print-schema`. Keep in
+/// sync with `JsonOutput` and `JsonFile`.
+pub const JSON_SCHEMA: &str = r#"{
+  "$schema": "https://json-schema.org/draft/2020-12/schema",
+  "title": "create-context JSON output",
+  "type": "object",
+  "properties": {
+    "tree": {
+      "description": "The directory tree of the included files, if shown",
+      "type": "string"
+    },
+    "files": {
+      "type": "array",
+      "items": {
+        "type": "object",
+        "properties": {
+          "path": { "type": "string" },
+          "language": { "type": "string" },
+          "content": { "type": "string" },
+          "token_count": { "type": "integer", "minimum": 0 },
+          "git_status": {
+            "description": "Present with --git-status for files that are not clean",
+            "enum": ["modified", "staged", "staged, modified", "untracked"]
+          },
+          "modified": {
+            "description": "Last modification time, present with --show-mtime",
+            "type": "string",
+            "format": "date-time"
+          }
+        },
+        "required": ["path", "language", "content", "token_count"],
+        "additionalProperties": false
+      }
+    }
+  },
+  "required": ["files"],
+  "additionalProperties": false
+}
+"#;
+
+/// Renders the processed files (and optionally the tree) as a pretty-printed
+/// JSON document.
+fn render_json(tree: Option<String>, files: &[ProcessedFile], bpe: &CoreBPE) -> io::Result<String> {
+    let output = JsonOutput {
+        tree,
+        files: files
+            .iter()
+            .map(|file| JsonFile {
+                path: &file.path,
+                language: &file.language,
+                content: &file.content,
+                token_count: count_tokens(bpe, &file.content),
+                git_status: file.git_status,
+                modified: file.modified.as_deref(),
+            })
+            .collect(),
+    };
+
+    let mut buf = serde_json::to_string_pretty(&output)?;
+    buf.push('\n');
+    Ok(buf)
+}
+
+/// Renders one file as a single line of JSON, for `Format::Jsonl`.
+pub fn render_jsonl_line(file: &ProcessedFile, token_count: usize) -> io::Result<String> {
+    let mut line = serde_json::to_string(&JsonFile {
+        path: &file.path,
+        language: &file.language,
+        content: &file.content,
+        token_count,
+        git_status: file.git_status,
+        modified: file.modified.as_deref(),
+    })?;
+    line.push('\n');
+    Ok(line)
+}
+
+/// Escapes `&`, `<`, `>` and quotes so text can go in XML content or
+/// attribute values.
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Renders a processed file as an XML `<document>` element, for
+/// `Format::Xml`.
+pub fn render_xml_document(file: &ProcessedFile) -> String {
+    let mut buf = format!(
+        "<document path=\"{}\" language=\"{}\"",
+        escape_xml(&file.path),
+        escape_xml(&file.language)
+    );
+    if let Some(state) = file.git_status {
+        buf.push_str(&format!(" git_status=\"{}\"", state));
+    }
+    if let Some(modified) = &file.modified {
+        buf.push_str(&format!(" modified=\"{}\"", modified));
+    }
+    buf.push_str(">\n<content>\n");
+    buf.push_str(&escape_xml(&file.content));
+    if !file.content.is_empty() && !file.content.ends_with('\n') {
+        buf.push('\n');
+    }
+    buf.push_str("</content>\n</document>\n");
+    buf
+}
+
+/// Renders the processed files (and optionally the tree) as an XML document
+/// with a `<documents>` root.
+fn render_xml(tree: Option<String>, files: &[ProcessedFile]) -> String {
+    let mut buf = String::from("<documents>\n");
+    if let Some(tree) = tree {
+        buf.push_str(&format!("<tree>\n{}\n</tree>\n", escape_xml(&tree)));
+    }
+    for file in files {
+        buf.push_str(&render_xml_document(file));
+    }
+    buf.push_str("</documents>\n");
+    buf
+}
+
+/// Renders processed files into a single document in the selected format.
+/// The tree is only embedded in JSON and XML output. Both JSON formats need a
+/// tokenizer for their per-file token counts.
+pub fn render_files(
+    files: &[ProcessedFile],
+    tree: Option<String>,
+    options: &RenderOptions,
+    bpe: Option<&CoreBPE>,
+) -> io::Result<String> {
+    Ok(render_chunks(files, tree, options, bpe)?.concat())
+}
+
+/// Like `render_files`, but returns the document in pieces that concatenate
+/// to it: the contents list and one piece per file for markdown and JSON
+/// lines, and the whole document for JSON and XML.
+pub fn render_chunks(
+    files: &[ProcessedFile],
+    tree: Option<String>,
+    options: &RenderOptions,
+    bpe: Option<&CoreBPE>,
+) -> io::Result<Vec<String>> {
+    match options.format {
+        Format::Markdown | Format::MarkdownCollapsible if options.toc => {
+            let anchors = anchors(files);
+            let toc = render_toc(files, &anchors);
+            Ok(std::iter::once(toc)
+                .chain(
+                    files
+                        .iter()
+                        .zip(&anchors)
+                        .map(|(file, anchor)| render_markdown_block(file, options, Some(anchor))),
+                )
+                .collect())
+        }
+        Format::Markdown | Format::MarkdownCollapsible => Ok(files
+            .iter()
+            .map(|file| render_markdown(file, options))
+            .collect()),
+        Format::Xml => Ok(vec![render_xml(tree, files)]),
+        Format::Json | Format::Jsonl => {
+            let bpe = bpe.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "JSON output requires a tokenizer",
+                )
+            })?;
+            if options.format == Format::Json {
+                return Ok(vec![render_json(tree, files, bpe)?]);
+            }
+            files
+                .iter()
+                .map(|file| render_jsonl_line(file, count_tokens(bpe, &file.content)))
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn file(path: &str, language: &str, content: &str) -> ProcessedFile {
+        ProcessedFile {
+            path: path.to_string(),
+            source: PathBuf::from(path),
+            language: language.to_string(),
+            content: content.to_string(),
+            git_status: None,
+            modified: None,
+            size: content.len() as u64,
+            sha256: None,
+        }
+    }
+
+    #[test]
+    fn test_json_header_outside_fence() {
+        let json = file("config/settings.json", "json", "{\"a\": 1}\n");
+        let rendered = render_markdown(&json, &RenderOptions::default());
+        assert_eq!(
+            rendered,
+            "config/settings.json\n```json\n{\"a\": 1}\n```\n\n"
+        );
+        assert!(!rendered.contains("//"));
+
+        let package = file("package.json", "node", "{}\n");
+        assert!(!render_markdown(&package, &RenderOptions::default()).contains("//"));
+    }
+
+    #[test]
+    fn test_toc_anchors_are_unique() {
+        let files = [
+            file("src/a_b.rs", "rust", ""),
+            file("src/a-b.rs", "rust", ""),
+            file("src/A.b.rs", "rust", ""),
+        ];
+        assert_eq!(
+            anchors(&files),
+            ["src-a-b-rs", "src-a-b-rs-2", "src-a-b-rs-3"]
+        );
+
+        let options = RenderOptions {
+            toc: true,
+            ..RenderOptions::default()
+        };
+        let rendered = render_files(&files[..1], None, &options, None).unwrap();
+        assert!(rendered.starts_with("## Contents\n\n- [src/a_b.rs](#src-a-b-rs)\n\n"));
+        assert!(rendered.contains("<a name=\"src-a-b-rs\"></a>\n```rust\n"));
+    }
+
+    #[test]
+    fn test_xml_escapes_content_and_attributes() {
+        let files = [file("src/a&b.rs", "rust", "if a < b && c > d {}")];
+        let options = RenderOptions {
+            format: Format::Xml,
+            ..RenderOptions::default()
+        };
+        assert_eq!(
+            render_files(&files, Some("a <dir>".to_string()), &options, None).unwrap(),
+            "<documents>\n<tree>\na &lt;dir&gt;\n</tree>\n\
+             <document path=\"src/a&amp;b.rs\" language=\"rust\">\n<content>\n\
+             if a &lt; b &amp;&amp; c &gt; d {}\n</content>\n</document>\n</documents>\n"
+        );
+    }
+
+    #[test]
+    fn test_collapsible_wraps_block_in_details() {
+        let rust = file("src/a<b>.rs", "rust", "fn main() {}\n");
+        let options = RenderOptions {
+            format: Format::MarkdownCollapsible,
+            ..RenderOptions::default()
+        };
+        assert_eq!(
+            render_markdown(&rust, &options),
+            "<details>\n<summary>src/a&lt;b&gt;.rs</summary>\n\n\
+             ```rust\n// src/a<b>.rs\nfn main() {}\n```\n\n</details>\n\n"
+        );
+    }
+
+    #[test]
+    fn test_header_notes() {
+        let mut rust = file("src/main.rs", "rust", "");
+        rust.git_status = Some("modified");
+        rust.modified = Some("2024-05-01T09:30:00Z".to_string());
+        assert!(render_markdown(&rust, &RenderOptions::default())
+            .contains("// src/main.rs (modified, 2024-05-01T09:30:00Z)\n"));
+        let mut css = file("style.css", "css", "");
+        css.modified = Some("2024-05-01T09:30:00Z".to_string());
+        assert!(render_markdown(&css, &RenderOptions::default())
+            .contains("/* style.css (2024-05-01T09:30:00Z) */\n"));
+    }
+
+    #[test]
+    fn test_comment_header_inside_fence() {
+        let rust = file("src/main.rs", "rust", "fn main() {}\n");
+        assert_eq!(
+            render_markdown(&rust, &RenderOptions::default()),
+            "```rust\n// src/main.rs\nfn main() {}\n```\n\n"
+        );
+        let css = file("style.css", "css", "a {}\n");
+        assert!(render_markdown(&css, &RenderOptions::default()).contains("/* style.css */\n"));
+    }
+}
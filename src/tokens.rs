@@ -0,0 +1,342 @@
+// ./src/tokens.rs
+use crate::process::ProcessedFile;
+use crate::render::{render_markdown, render_xml_document, Format, RenderOptions};
+use clap::ValueEnum;
+use rayon::prelude::*;
+use std::io;
+use tiktoken_rs::{cl100k_base, o200k_base, p50k_base, CoreBPE};
+
+/// Tokenizers available for token counting
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Tokenizer {
+    /// cl100k_base (GPT-4 / GPT-3.5)
+    #[default]
+    Cl100k,
+    /// o200k_base (GPT-4o)
+    O200k,
+    /// p50k_base (Codex / text-davinci)
+    P50k,
+}
+
+/// Load the BPE data for the selected tokenizer
+pub fn load_tokenizer(tokenizer: Tokenizer) -> io::Result<CoreBPE> {
+    let bpe = match tokenizer {
+        Tokenizer::Cl100k => cl100k_base(),
+        Tokenizer::O200k => o200k_base(),
+        Tokenizer::P50k => p50k_base(),
+    };
+    bpe.map_err(|e| io::Error::other(format!("Failed to load tokenizer {:?}: {}", tokenizer, e)))
+}
+
+/// Count tokens using the given tokenizer
+pub fn count_tokens(bpe: &CoreBPE, text: &str) -> usize {
+    bpe.encode_with_special_tokens(text).len()
+}
+
+/// Texts are split into chunks of about this many bytes by
+/// `count_tokens_chunked`.
+const TOKEN_CHUNK_LEN: usize = 64 * 1024;
+
+/// Splits `text` into pieces of about `max_len` bytes, each ending just after
+/// a newline where there is one.
+fn line_chunks(text: &str, max_len: usize) -> impl Iterator<Item = &str> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let end = if rest.len() <= max_len {
+            rest.len()
+        } else {
+            let mut cut = max_len;
+            while !rest.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            rest[..cut]
+                .rfind('\n')
+                .or_else(|| rest[cut..].find('\n').map(|i| cut + i))
+                .map_or(rest.len(), |i| i + 1)
+        };
+        let (chunk, tail) = rest.split_at(end);
+        rest = tail;
+        Some(chunk)
+    })
+}
+
+/// Counts the tokens in `text` a chunk of lines at a time and sums them, so
+/// the token vector for a large output is never held all at once. This only
+/// bounds the memory used for tokens; `text` itself is still one string.
+///
+/// BPE is not additive across chunk boundaries: no token can span one, so
+/// the sum can differ from `count_tokens` on the whole text. Splitting only
+/// after a newline keeps the difference to at most a token or so per
+/// `TOKEN_CHUNK_LEN` bytes, which is negligible for reporting.
+pub fn count_tokens_chunked(bpe: &CoreBPE, text: &str) -> usize {
+    line_chunks(text, TOKEN_CHUNK_LEN)
+        .map(|chunk| count_tokens(bpe, chunk))
+        .sum()
+}
+
+/// Counts the tokens a file contributes to the output in the given format.
+pub fn file_tokens(bpe: &CoreBPE, file: &ProcessedFile, options: &RenderOptions) -> usize {
+    match options.format {
+        Format::Markdown | Format::MarkdownCollapsible => {
+            count_tokens(bpe, &render_markdown(file, options))
+        }
+        Format::Json | Format::Jsonl => count_tokens(bpe, &file.content),
+        Format::Xml => count_tokens(bpe, &render_xml_document(file)),
+    }
+}
+
+/// Counts the tokens each file contributes, in the order of `files`. With
+/// `parallel`, files are tokenized across threads sharing the one `bpe`.
+pub fn file_token_counts(
+    bpe: &CoreBPE,
+    files: &[ProcessedFile],
+    options: &RenderOptions,
+    parallel: bool,
+) -> Vec<usize> {
+    if parallel {
+        files
+            .par_iter()
+            .map(|file| file_tokens(bpe, file, options))
+            .collect()
+    } else {
+        files
+            .iter()
+            .map(|file| file_tokens(bpe, file, options))
+            .collect()
+    }
+}
+
+/// Reports every file whose content is over `limit` tokens, and with `skip`
+/// leaves it out of `files`. Only the content counts, not the header or
+/// fence around it, so the limit means the same thing in every format.
+pub fn flag_large_files(
+    files: &mut Vec<ProcessedFile>,
+    limit: usize,
+    skip: bool,
+    bpe: &CoreBPE,
+    parallel: bool,
+) {
+    let counts: Vec<usize> = if parallel {
+        files
+            .par_iter()
+            .map(|file| count_tokens(bpe, &file.content))
+            .collect()
+    } else {
+        files
+            .iter()
+            .map(|file| count_tokens(bpe, &file.content))
+            .collect()
+    };
+    let mut counts = counts.into_iter();
+    files.retain(|file| {
+        let tokens = counts.next().unwrap_or(0);
+        if tokens <= limit {
+            return true;
+        }
+        if skip {
+            warn!(
+                "Skipped '{}': {} tokens exceeds the per-file limit of {}",
+                file.path, tokens, limit
+            );
+        } else {
+            warn!(
+                "Warning: '{}' has {} tokens, over the per-file limit of {}",
+                file.path, tokens, limit
+            );
+        }
+        !skip
+    });
+}
+
+/// Marker appended to a file cut short by `truncate_file`.
+pub const TRUNCATED_MARKER: &str = "... [truncated]";
+
+/// Cuts a file whose content is over `limit` tokens down to its first
+/// `limit` tokens, followed by `TRUNCATED_MARKER` on its own line.
+pub fn truncate_file(file: &mut ProcessedFile, limit: usize, bpe: &CoreBPE) {
+    let tokens = bpe.encode_with_special_tokens(&file.content);
+    if tokens.len() <= limit {
+        return;
+    }
+    // A token can end partway through a multi-byte character, which does not
+    // decode on its own; back off until the prefix does
+    let mut end = limit;
+    let mut content = loop {
+        match bpe.decode(tokens[..end].to_vec()) {
+            Ok(text) => break text,
+            Err(_) if end > 0 => end -= 1,
+            Err(_) => break String::new(),
+        }
+    };
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    content.push_str(TRUNCATED_MARKER);
+    content.push('\n');
+    warn!(
+        "Truncated '{}' from {} to {} tokens",
+        file.path,
+        tokens.len(),
+        end
+    );
+    file.content = content;
+}
+
+/// Applies `truncate_file` to every file, across threads with `parallel`.
+pub fn truncate_files(files: &mut [ProcessedFile], limit: usize, bpe: &CoreBPE, parallel: bool) {
+    if parallel {
+        files
+            .par_iter_mut()
+            .for_each(|file| truncate_file(file, limit, bpe));
+    } else {
+        files
+            .iter_mut()
+            .for_each(|file| truncate_file(file, limit, bpe));
+    }
+}
+
+/// Keeps files in order until including the next one would push the total,
+/// plus the `reserved` tokens of text around them, over `max_tokens`; that
+/// file and every file after it are dropped. In parallel mode every file is
+/// counted up front; otherwise counting stops at the first file over budget.
+pub fn apply_token_budget(
+    files: &mut Vec<ProcessedFile>,
+    max_tokens: usize,
+    reserved: usize,
+    bpe: &CoreBPE,
+    options: &RenderOptions,
+    parallel: bool,
+) {
+    let counts = parallel.then(|| file_token_counts(bpe, files, options, true));
+    let mut used = reserved;
+    let mut keep = files.len();
+    for (i, file) in files.iter().enumerate() {
+        let tokens = counts
+            .as_ref()
+            .map_or_else(|| file_tokens(bpe, file, options), |counts| counts[i]);
+        if used + tokens > max_tokens {
+            keep = i;
+            break;
+        }
+        used += tokens;
+    }
+
+    for file in &files[keep..] {
+        warn!(
+            "Dropped '{}': token budget of {} exceeded",
+            file.path, max_tokens
+        );
+    }
+    files.truncate(keep);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn file(path: &str, content: &str) -> ProcessedFile {
+        ProcessedFile {
+            path: path.to_string(),
+            source: PathBuf::from(path),
+            language: "text".to_string(),
+            content: content.to_string(),
+            git_status: None,
+            modified: None,
+            size: content.len() as u64,
+            sha256: None,
+        }
+    }
+
+    #[test]
+    fn test_line_chunks() {
+        let text = "fn a() {}\nfn b() {}\nfn c() {}\n";
+        let chunks: Vec<&str> = line_chunks(text, 15).collect();
+        assert_eq!(chunks, ["fn a() {}\n", "fn b() {}\n", "fn c() {}\n"]);
+        // A line longer than the limit is kept whole
+        assert_eq!(line_chunks("é".repeat(10).as_str(), 3).count(), 1);
+        assert_eq!(line_chunks("", 3).count(), 0);
+    }
+
+    #[test]
+    fn test_chunked_count_close_to_whole() {
+        let bpe = load_tokenizer(Tokenizer::default()).unwrap();
+        let line = "    let total = items.iter().map(|item| item.len()).sum::<usize>();\n";
+        let text = line.repeat(4 * TOKEN_CHUNK_LEN / line.len());
+        let chunks = line_chunks(&text, TOKEN_CHUNK_LEN).count();
+        assert!(chunks > 1);
+        let whole = count_tokens(&bpe, &text);
+        assert!(count_tokens_chunked(&bpe, &text).abs_diff(whole) <= chunks);
+    }
+
+    #[test]
+    fn test_truncate_inside_a_character() {
+        let bpe = load_tokenizer(Tokenizer::default()).unwrap();
+        let text = "日本語のテキスト 🦀🦀 ".repeat(20);
+        let tokens = bpe.encode_with_special_tokens(&text);
+        // A limit whose prefix of tokens stops partway through a character
+        let limit = (1..tokens.len())
+            .find(|&n| bpe.decode(tokens[..n].to_vec()).is_err())
+            .unwrap();
+
+        let mut truncated = file("ja.txt", &text);
+        truncate_file(&mut truncated, limit, &bpe);
+        let kept = truncated
+            .content
+            .strip_suffix(&format!("{TRUNCATED_MARKER}\n"))
+            .unwrap();
+        let kept = kept.strip_suffix('\n').unwrap_or(kept);
+        assert!(!kept.is_empty() && text.starts_with(kept));
+        assert!(count_tokens(&bpe, kept) <= limit);
+    }
+
+    #[test]
+    fn test_token_budget_stops_at_first_overflow() {
+        let bpe = load_tokenizer(Tokenizer::default()).unwrap();
+        let options = RenderOptions::default();
+        let files = vec![
+            file("a.rs", "fn a() {}\n"),
+            file("b.rs", &"fn b() {}\n".repeat(50)),
+            file("c.rs", "\n"),
+        ];
+        // Room for a.rs and c.rs, but not b.rs
+        let max_tokens =
+            file_tokens(&bpe, &files[0], &options) + file_tokens(&bpe, &files[2], &options) + 1;
+        assert!(
+            max_tokens
+                < file_tokens(&bpe, &files[0], &options) + file_tokens(&bpe, &files[1], &options)
+        );
+
+        for parallel in [false, true] {
+            let mut kept = files.clone();
+            apply_token_budget(&mut kept, max_tokens, 0, &bpe, &options, parallel);
+            let paths: Vec<&str> = kept.iter().map(|file| file.path.as_str()).collect();
+            assert_eq!(paths, ["a.rs"], "parallel: {parallel}");
+        }
+    }
+
+    #[test]
+    fn test_large_files_measure_content_only() {
+        let bpe = load_tokenizer(Tokenizer::default()).unwrap();
+        let files = vec![
+            file("empty.rs", ""),
+            file("big.rs", &"fn b() {}\n".repeat(50)),
+        ];
+        let limit = count_tokens(&bpe, &files[1].content) - 1;
+
+        for parallel in [false, true] {
+            let mut kept = files.clone();
+            flag_large_files(&mut kept, limit, true, &bpe, parallel);
+            let paths: Vec<&str> = kept.iter().map(|file| file.path.as_str()).collect();
+            assert_eq!(paths, ["empty.rs"], "parallel: {parallel}");
+
+            // An empty file has no tokens, whatever its header would add
+            let mut kept = files[..1].to_vec();
+            flag_large_files(&mut kept, 0, true, &bpe, parallel);
+            assert_eq!(kept.len(), 1, "parallel: {parallel}");
+        }
+    }
+}
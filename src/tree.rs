@@ -0,0 +1,309 @@
+// ./src/tree.rs
+use crate::patterns::{dotdot_relative, relative_path};
+use crate::tokens::count_tokens;
+use std::collections::{btree_map, BTreeMap};
+use std::ffi::OsString;
+use std::fs;
+use std::iter::Peekable;
+use std::path::{Component, Path, PathBuf};
+use tiktoken_rs::CoreBPE;
+
+/// A directory in the tree, built from the list of files to show. Both maps
+/// are ordered by name; directories are listed before files.
+#[derive(Default)]
+struct Node {
+    dirs: BTreeMap<OsString, Node>,
+    files: BTreeMap<OsString, PathBuf>,
+}
+
+impl Node {
+    fn build(root: &Path, files: &[PathBuf]) -> Node {
+        let canonical_root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+        let mut tree = Node::default();
+        for path in files {
+            let mut relative = relative_path(path, root, &canonical_root).to_path_buf();
+            // A file outside the root hangs off a `..` branch instead
+            if relative.is_absolute() {
+                let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+                relative = dotdot_relative(&canonical, &canonical_root);
+            }
+            let mut parts: Vec<OsString> = relative
+                .components()
+                .filter_map(|c| match c {
+                    Component::Normal(part) => Some(part.to_os_string()),
+                    Component::ParentDir => Some(OsString::from("..")),
+                    _ => None,
+                })
+                .collect();
+            let Some(name) = parts.pop() else {
+                continue;
+            };
+            let mut node = &mut tree;
+            for part in parts {
+                node = node.dirs.entry(part).or_default();
+            }
+            node.files.insert(name, path.clone());
+        }
+        tree
+    }
+}
+
+/// Lines and counts accumulated while walking the directory tree.
+#[derive(Default)]
+struct TreeOutput {
+    lines: Vec<String>,
+    dir_count: usize,
+    file_count: usize,
+}
+
+/// Size and token totals shown by `--tree-annotate`.
+#[derive(Clone, Copy, Default)]
+struct Totals {
+    bytes: u64,
+    tokens: usize,
+}
+
+impl Totals {
+    fn add(&mut self, other: Totals) {
+        self.bytes += other.bytes;
+        self.tokens += other.tokens;
+    }
+}
+
+/// Formats a byte count with a binary unit, e.g. `512 B` or `12 KB`.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if value < 10.0 {
+        format!("{:.1} {}", value, UNITS[unit])
+    } else {
+        format!("{:.0} {}", value, UNITS[unit])
+    }
+}
+
+fn file_totals(path: &Path, bpe: Option<&CoreBPE>) -> Totals {
+    let bytes = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+    // Files that are not valid UTF-8 count as zero tokens
+    let tokens = bpe.map_or(0, |bpe| {
+        fs::read_to_string(path).map_or(0, |text| count_tokens(bpe, &text))
+    });
+    Totals { bytes, tokens }
+}
+
+fn annotation(totals: Totals, ctx: &TreeContext) -> String {
+    match ctx.bpe {
+        Some(_) => format!(" ({}, {} tokens)", format_size(totals.bytes), totals.tokens),
+        None => format!(" ({})", format_size(totals.bytes)),
+    }
+}
+
+/// Connector strings used to draw the tree.
+pub struct TreeStyle {
+    pub branch: &'static str,
+    pub last: &'static str,
+    pub pipe: &'static str,
+}
+
+pub const UNICODE_TREE: TreeStyle = TreeStyle {
+    branch: "├── ",
+    last: "└── ",
+    pipe: "│   ",
+};
+
+pub const ASCII_TREE: TreeStyle = TreeStyle {
+    branch: "|-- ",
+    last: "`-- ",
+    pipe: "|   ",
+};
+
+/// Settings shared by every level of the tree walk.
+pub struct TreeContext<'a> {
+    pub root: &'a Path,
+    /// The files to show, as paths under `root`. Directories are derived
+    /// from them, so the tree matches exactly what is collected.
+    pub files: &'a [PathBuf],
+    pub max_depth: Option<usize>,
+    pub style: &'a TreeStyle,
+    /// Append sizes (and token counts, when `bpe` is set) to each entry.
+    pub annotate: bool,
+    pub bpe: Option<&'a CoreBPE>,
+}
+
+/// A directory whose line has been written but whose children are still
+/// being walked.
+struct Frame<'n> {
+    dirs: Peekable<btree_map::Iter<'n, OsString, Node>>,
+    files: &'n BTreeMap<OsString, PathBuf>,
+    prefix: String,
+    depth: usize,
+    dir_line: usize,
+    totals: Totals,
+}
+
+impl<'n> Frame<'n> {
+    fn new(node: &'n Node, prefix: String, depth: usize, dir_line: usize) -> Self {
+        Frame {
+            dirs: node.dirs.iter().peekable(),
+            files: &node.files,
+            prefix,
+            depth,
+            dir_line,
+            totals: Totals::default(),
+        }
+    }
+}
+
+/// Walks the tree depth first with an explicit stack, so arbitrarily deep
+/// directories cannot overflow the call stack.
+fn walk_tree(root: &Node, out: &mut TreeOutput, ctx: &TreeContext) {
+    let is_cut_off = |depth: usize| ctx.max_depth.is_some_and(|max| depth >= max);
+
+    out.lines.push(".".to_string());
+    // Directories cut off by the depth limit are not annotated
+    if is_cut_off(0) {
+        return;
+    }
+    let mut stack = vec![Frame::new(root, "    ".to_string(), 0, 0)];
+
+    while let Some(frame) = stack.last_mut() {
+        if let Some((name, child)) = frame.dirs.next() {
+            let is_last = frame.dirs.peek().is_none() && frame.files.is_empty();
+            let (conn, pipe) = if is_last {
+                (ctx.style.last, "    ")
+            } else {
+                (ctx.style.branch, ctx.style.pipe)
+            };
+            out.dir_count += 1;
+            out.lines
+                .push(format!("{}{conn}{}", frame.prefix, name.to_string_lossy()));
+            let depth = frame.depth + 1;
+            if !is_cut_off(depth) {
+                let prefix = format!("{}{pipe}", frame.prefix);
+                stack.push(Frame::new(child, prefix, depth, out.lines.len() - 1));
+            }
+            continue;
+        }
+
+        // Every subdirectory is done; list the files and close the directory
+        let mut frame = stack.pop().unwrap();
+        let mut files = frame.files.iter().peekable();
+        while let Some((name, path)) = files.next() {
+            let conn = if files.peek().is_none() {
+                ctx.style.last
+            } else {
+                ctx.style.branch
+            };
+            out.file_count += 1;
+            let mut line = format!("{}{conn}{}", frame.prefix, name.to_string_lossy());
+            if ctx.annotate {
+                let file = file_totals(path, ctx.bpe);
+                line.push_str(&annotation(file, ctx));
+                frame.totals.add(file);
+            }
+            out.lines.push(line);
+        }
+
+        if ctx.annotate {
+            out.lines[frame.dir_line].push_str(&annotation(frame.totals, ctx));
+        }
+        if let Some(parent) = stack.last_mut() {
+            parent.totals.add(frame.totals);
+        }
+    }
+}
+
+/// Renders the files under `root` as a directory tree in plain text,
+/// followed by a summary line with the directory and file counts.
+pub fn render_tree(ctx: &TreeContext) -> String {
+    let mut out = TreeOutput {
+        dir_count: 1,
+        ..Default::default()
+    };
+    let tree = Node::build(ctx.root, ctx.files);
+    walk_tree(&tree, &mut out, ctx);
+
+    let mut text = out.lines.join("\n");
+    text.push_str(&format!(
+        "\n\n{} directories, {} files",
+        out.dir_count, out.file_count
+    ));
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(12 * 1024 + 100), "12 KB");
+        assert_eq!(format_size(3 * 1024 * 1024), "3.0 MB");
+    }
+
+    #[test]
+    fn test_very_deep_tree() {
+        const DEPTH: usize = 1000;
+        let tmp = tempfile::tempdir().unwrap();
+        let mut dir = tmp.path().to_path_buf();
+        for _ in 0..DEPTH {
+            dir.push("d");
+        }
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("deep.txt");
+        fs::write(&file, "bottom").unwrap();
+
+        let files = [file];
+        let tree = render_tree(&TreeContext {
+            root: tmp.path(),
+            files: &files,
+            max_depth: None,
+            style: &ASCII_TREE,
+            annotate: true,
+            bpe: None,
+        });
+        let lines: Vec<&str> = tree.lines().collect();
+        assert_eq!(lines[0], ". (6 B)");
+        assert!(lines[DEPTH + 1].ends_with("`-- deep.txt (6 B)"));
+        assert!(tree.ends_with(&format!("{} directories, 1 files", DEPTH + 1)));
+    }
+
+    #[test]
+    fn test_file_outside_root() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("project");
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::create_dir_all(tmp.path().join("other")).unwrap();
+        let inside = root.join("src").join("main.rs");
+        let outside = tmp.path().join("other").join("o.rs");
+        fs::write(&inside, "").unwrap();
+        fs::write(&outside, "").unwrap();
+
+        // Explicit files outside `--dir` arrive canonicalized
+        let files = [inside, outside.canonicalize().unwrap()];
+        let tree = render_tree(&TreeContext {
+            root: &root,
+            files: &files,
+            max_depth: None,
+            style: &ASCII_TREE,
+            annotate: false,
+            bpe: None,
+        });
+        // The `..` branch names no directory above the root
+        let lines: Vec<&str> = tree.lines().map(str::trim_start).collect();
+        assert_eq!(
+            lines[..4],
+            [".", "|-- ..", "|   `-- other", "|       `-- o.rs"]
+        );
+        assert!(!tree.contains(&*tmp.path().to_string_lossy()));
+    }
+}
@@ -0,0 +1,45 @@
+// ./tests/global_gitignore.rs
+//! Runs the binary against a fake user git config. The ignore crate reads
+//! `core.excludesFile` from `$HOME/.gitconfig` or
+//! `$XDG_CONFIG_HOME/git/config` itself, so the child process gets its own
+//! environment and the test process's is left alone.
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Runs `create-context --list` on `root` with `home` as the user's home.
+fn list(root: &Path, home: &Path, extra: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_create-context"))
+        .args(["--dir", root.to_str().unwrap(), "--patterns", "*", "--list"])
+        .args(extra)
+        .env("HOME", home)
+        .env("XDG_CONFIG_HOME", home.join(".config"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_global_gitignore() {
+    let home = tempfile::tempdir().unwrap();
+    let global = home.path().join("global-ignore");
+    fs::write(&global, "*.secret\n").unwrap();
+    fs::write(
+        home.path().join(".gitconfig"),
+        format!("[core]\n\texcludesFile = {}\n", global.display()),
+    )
+    .unwrap();
+
+    let tmp = tempfile::tempdir().unwrap();
+    let root = tmp.path();
+    fs::create_dir(root.join(".git")).unwrap();
+    fs::write(root.join("main.rs"), "").unwrap();
+    fs::write(root.join("key.secret"), "").unwrap();
+
+    assert_eq!(list(root, home.path(), &[]), "main.rs\n");
+    assert_eq!(
+        list(root, home.path(), &["--no-global-gitignore"]),
+        "key.secret\nmain.rs\n"
+    );
+}